@@ -0,0 +1,35 @@
+use sha2::{Digest, Sha256};
+
+/// A running SHA-256 computation, as used to verify partitions and operation data.
+pub trait Sha256Hasher {
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest of everything fed so far and resets the hasher for reuse.
+    fn finalize_reset(&mut self) -> [u8; 32];
+}
+
+/// Creates the hashers a payload verifies with. The default is [`SoftwareHash`]; devices
+/// with hardware-accelerated SHA-256, such as through Android's `MessageDigest`, can plug
+/// that in with [`Payload::set_hash_provider`](crate::payload::Payload::set_hash_provider).
+pub trait HashProvider: Send + Sync {
+    fn sha256(&self) -> Box<dyn Sha256Hasher>;
+}
+
+/// SHA-256 in software, from the `sha2` crate.
+pub struct SoftwareHash;
+
+impl HashProvider for SoftwareHash {
+    fn sha256(&self) -> Box<dyn Sha256Hasher> {
+        Box::new(Sha256::new())
+    }
+}
+
+impl Sha256Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize_reset(&mut self) -> [u8; 32] {
+        Digest::finalize_reset(self).into()
+    }
+}
@@ -1,13 +1,18 @@
 use jni::objects::{JClass, JObject, JString, JValue};
 use jni::JNIEnv;
-use payload::Payload;
-use std::cell::RefCell;
+use payload::{ExtractOptions, Payload};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::error::Error;
 
 use jni::sys::jstring;
 
+#[allow(dead_code, clippy::all)]
 mod chromeos_update_engine;
-mod payload;
+pub mod hash;
+pub mod payload;
+pub mod sink;
+pub mod sparse;
 
 #[no_mangle]
 pub extern "system" fn Java_com_rajmani7584_payloaddumper_PayloadDumper_getPartitionList<'local>(
@@ -27,7 +32,7 @@ pub extern "system" fn Java_com_rajmani7584_payloaddumper_PayloadDumper_getParti
         }
     };
 
-    let _ = match payload.get_partition_list() {
+    match payload.get_partition_list() {
         Ok(res) => {
             msg.insert_str(msg.len(), &res);
         }
@@ -39,9 +44,7 @@ pub extern "system" fn Java_com_rajmani7584_payloaddumper_PayloadDumper_getParti
         }
     };
 
-    let msg = env.new_string(msg).expect("Error:expect").into_raw();
-
-    return msg;
+    env.new_string(msg).expect("Error:expect").into_raw()
 }
 
 #[no_mangle]
@@ -117,7 +120,7 @@ pub extern "system" fn Java_com_rajmani7584_payloaddumper_PayloadDumper_extractP
         })?;
 
         if !msg_c.borrow().is_empty() {
-            return Err(format!("{}", msg).as_str().into());
+            return Err(msg.to_string().into());
         }
 
         Ok(e)
@@ -130,3 +133,222 @@ pub extern "system" fn Java_com_rajmani7584_payloaddumper_PayloadDumper_extractP
     let x = env_c.borrow().new_string(msg).unwrap().into_raw();
     x
 }
+
+/// Extracts several partitions into `out_dir`. `partitions` is a comma separated list of
+/// names, or empty for every partition. Progress goes to
+/// `onBatchProgressCallback(String partition, long partitionPercent, long overallPercent)`,
+/// where the overall share is weighted by partition size.
+#[no_mangle]
+pub extern "system" fn Java_com_rajmani7584_payloaddumper_PayloadDumper_extractPartitions(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+    partitions: JString,
+    out_dir: JString,
+    callback: JObject,
+) -> jstring {
+    let path: String = match env.get_string(&path) {
+        Ok(p) => p.into(),
+        Err(_) => {
+            return env
+                .new_string("Error: Failed to get path")
+                .unwrap()
+                .into_raw()
+        }
+    };
+
+    let partitions: String = match env.get_string(&partitions) {
+        Ok(p) => p.into(),
+        Err(_) => {
+            return env
+                .new_string("Error: Failed to get partitions")
+                .unwrap()
+                .into_raw()
+        }
+    };
+
+    let out_dir: String = match env.get_string(&out_dir) {
+        Ok(p) => p.into(),
+        Err(_) => {
+            return env
+                .new_string("Error: Failed to get output path")
+                .unwrap()
+                .into_raw()
+        }
+    };
+
+    let env_c = RefCell::new(env);
+
+    let msg_c = RefCell::new(String::new());
+    let result = (|| -> Result<String, Box<dyn Error>> {
+        let mut payload = Payload::new(path)?;
+
+        let names: Vec<String> = partitions
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+        let sizes = partition_sizes(&mut payload, &names)?;
+        let total_size = sizes.values().sum::<u64>().max(1);
+        let done_size = Cell::new(0u64);
+
+        let results = payload.extract_partitions(&names, &out_dir, &ExtractOptions::default(), &|name, progress| {
+            let size = sizes.get(name).copied().unwrap_or(0);
+            let overall = (done_size.get() + size * progress as u64 / 100) * 100 / total_size;
+            if let Err(err) = report_batch_progress(&mut env_c.borrow_mut(), &callback, name, progress, overall) {
+                msg_c.borrow_mut().push_str(&format!("{}", err));
+            }
+        }, &|_, verifi_status| {
+            let mut env_cloned = env_c.borrow_mut();
+            if let Err(err) = env_cloned.call_method(
+                &callback,
+                "onVerifyCallback",
+                "(I)V",
+                &[JValue::from(verifi_status as i32)],
+            ) {
+                msg_c.borrow_mut().push_str(&format!("{}", err));
+            }
+        }, &|result| {
+            done_size.set(done_size.get() + result.size);
+        })?;
+
+        if !msg_c.borrow().is_empty() {
+            return Err(msg_c.borrow().clone().into());
+        }
+
+        let failed: Vec<String> = results
+            .iter()
+            .filter(|result| !result.verified())
+            .map(|result| format!("{} ({})", result.partition, result.error.as_deref().unwrap_or("Partition Hash mismatch error")))
+            .collect();
+        if !failed.is_empty() {
+            return Err(format!("failed partitions: {}", failed.join(", ")).into());
+        }
+
+        Ok("Done".into())
+    })();
+
+    let msg = match result {
+        Ok(msg) => msg,
+        Err(err) => format!("Error: {}", err),
+    };
+    let x = env_c.borrow().new_string(msg).unwrap().into_raw();
+    x
+}
+
+/// Checks a payload without extracting anything: every partition is decoded and hashed in
+/// memory. Progress goes to `onBatchProgressCallback` as for `extractPartitions`, and each
+/// partition's outcome to `onPartitionVerifiedCallback(String partition, boolean ok,
+/// String message)`. Returns `OK:<metadata hash>` when every partition matches, with the
+/// metadata hash as hex; `payload_properties.txt` has the same SHA256 as `METADATA_HASH`,
+/// base64 encoded. Partitions written out of order can't be checked this way. They are
+/// reported as not ok with the reason, and if no partition failed outright the result is
+/// `PARTIAL:<metadata hash>:<unchecked partitions>`, comma separated.
+#[no_mangle]
+pub extern "system" fn Java_com_rajmani7584_payloaddumper_PayloadDumper_verifyPayload(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+    callback: JObject,
+) -> jstring {
+    let path: String = match env.get_string(&path) {
+        Ok(p) => p.into(),
+        Err(_) => {
+            return env
+                .new_string("Error: Failed to get path")
+                .unwrap()
+                .into_raw()
+        }
+    };
+
+    let env_c = RefCell::new(env);
+
+    let msg_c = RefCell::new(String::new());
+    let result = (|| -> Result<String, Box<dyn Error>> {
+        let mut payload = Payload::new(path)?;
+        let metadata_hash = payload.metadata_hash()?;
+
+        let sizes = partition_sizes(&mut payload, &[])?;
+        let total_size = sizes.values().sum::<u64>().max(1);
+        let done_size = Cell::new(0u64);
+
+        let results = payload.verify_all(&|name, progress| {
+            let size = sizes.get(name).copied().unwrap_or(0);
+            let overall = (done_size.get() + size * progress as u64 / 100) * 100 / total_size;
+            if let Err(err) = report_batch_progress(&mut env_c.borrow_mut(), &callback, name, progress, overall) {
+                msg_c.borrow_mut().push_str(&format!("{}", err));
+            }
+        }, &|_, _| {}, &|result| {
+            done_size.set(done_size.get() + result.size);
+            let mut env_cloned = env_c.borrow_mut();
+            let reported = (|| -> jni::errors::Result<()> {
+                let name = env_cloned.new_string(&result.partition)?;
+                let message = env_cloned.new_string(result.error.as_deref().unwrap_or(if result.verified() { "" } else { "Partition Hash mismatch error" }))?;
+                env_cloned.call_method(
+                    &callback,
+                    "onPartitionVerifiedCallback",
+                    "(Ljava/lang/String;ZLjava/lang/String;)V",
+                    &[JValue::from(&name), JValue::from(result.verified()), JValue::from(&message)],
+                )?;
+                env_cloned.delete_local_ref(name)?;
+                env_cloned.delete_local_ref(message)
+            })();
+            if let Err(err) = reported {
+                msg_c.borrow_mut().push_str(&format!("{}", err));
+            }
+        })?;
+
+        if !msg_c.borrow().is_empty() {
+            return Err(msg_c.borrow().clone().into());
+        }
+
+        let failed: Vec<&str> = results
+            .iter()
+            .filter(|result| !result.verified() && !result.unverifiable)
+            .map(|result| result.partition.as_str())
+            .collect();
+        if !failed.is_empty() {
+            return Err(format!("failed partitions: {}", failed.join(", ")).into());
+        }
+        let unchecked: Vec<&str> = results
+            .iter()
+            .filter(|result| result.unverifiable)
+            .map(|result| result.partition.as_str())
+            .collect();
+        if !unchecked.is_empty() {
+            return Ok(format!("PARTIAL:{}:{}", metadata_hash, unchecked.join(",")));
+        }
+
+        Ok(format!("OK:{}", metadata_hash))
+    })();
+
+    let msg = match result {
+        Ok(msg) => msg,
+        Err(err) => format!("Error: {}", err),
+    };
+    let x = env_c.borrow().new_string(msg).unwrap().into_raw();
+    x
+}
+
+/// Sizes of the partitions in `names`, or of every partition if it is empty.
+fn partition_sizes(payload: &mut Payload, names: &[String]) -> Result<HashMap<String, u64>, Box<dyn Error>> {
+    Ok(payload
+        .partitions()?
+        .into_iter()
+        .filter(|p| names.is_empty() || names.contains(&p.name))
+        .map(|p| (p.name, p.size))
+        .collect())
+}
+
+fn report_batch_progress(env: &mut JNIEnv, callback: &JObject, partition: &str, progress: usize, overall: u64) -> jni::errors::Result<()> {
+    let name = env.new_string(partition)?;
+    env.call_method(
+        callback,
+        "onBatchProgressCallback",
+        "(Ljava/lang/String;JJ)V",
+        &[JValue::from(&name), JValue::from(progress as i64), JValue::from(overall as i64)],
+    )?;
+    // This runs once per operation, so free the name instead of piling up local refs.
+    env.delete_local_ref(name)
+}
@@ -1,14 +1,39 @@
-use std::{error::Error, fs::File, io::{self, BufReader, Read, Seek, SeekFrom, Write}, str};
+use std::{collections::HashMap, error::Error, fs::{self, File}, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{Arc, OnceLock}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use bzip2::read::BzDecoder;
+use crc::{Crc, CRC_32_ISO_HDLC};
 use liblzma::read::XzDecoder;
-use sha2::{Sha256, Digest};
-use zip::ZipArchive;
+use memmap2::Mmap;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
-use crate::chromeos_update_engine::{install_operation::Type, DeltaArchiveManifest, PartitionUpdate};
+use crate::chromeos_update_engine::{install_operation::Type, DeltaArchiveManifest, Extent, InstallOperation, PartitionUpdate, Signatures};
+use crate::hash::{HashProvider, Sha256Hasher, SoftwareHash};
+use crate::sink::{FileSink, NullSink, OutputSink, StreamingSink};
+use crate::sparse::SparseSink;
+
+pub use crate::chromeos_update_engine::install_operation::Type as OperationKind;
 
 const PAYLOAD_HEADER_MAGIC: &str = "CrAU";
 const BRILLO_MAJOR_PAYLOAD_VERSION: u64 = 2;
 const BLOCK_SIZE: u64 = 4096;
+/// How far past the expected start to look for the magic before giving up.
+const MAGIC_SCAN_LIMIT: u64 = 8 * 1024;
+/// Partitions that rooting and modding usually need, for [`Payload::export_patch_pack`].
+const PATCH_PACK_PARTITIONS: [&str; 4] = ["boot", "init_boot", "dtbo", "vbmeta"];
+/// How much of a file without the magic is checked for looking like text.
+const TEXT_PROBE_LEN: u64 = 512;
+/// Largest operation preferred as a decompression sample by [`Payload::estimate_duration`].
+const ESTIMATE_SAMPLE_LIMIT: u64 = 4 * 1024 * 1024;
+/// Bytes written to the output directory to gauge storage speed.
+const WRITE_PROBE_SIZE: usize = 4 * 1024 * 1024;
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+/// `PAYLOAD_HEADER_MAGIC` compressed with xz, for [`probe_xz`].
+const XZ_PROBE: [u8; 60] = [
+    0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x04, 0xe6, 0xd6, 0xb4, 0x46, 0x02, 0x00, 0x21, 0x01,
+    0x16, 0x00, 0x00, 0x00, 0x74, 0x2f, 0xe5, 0xa3, 0x01, 0x00, 0x03, 0x43, 0x72, 0x41, 0x55, 0x00,
+    0x00, 0x7c, 0x37, 0x6e, 0xe2, 0xc8, 0xf7, 0x96, 0x00, 0x01, 0x1c, 0x04, 0x6f, 0x2c, 0x9c, 0xc1,
+    0x1f, 0xb6, 0xf3, 0x7d, 0x01, 0x00, 0x00, 0x00, 0x00, 0x04, 0x59, 0x5a,
+];
+const SUPPORTED_OPERATIONS: [OperationKind; 4] = [OperationKind::Replace, OperationKind::ReplaceBz, OperationKind::ReplaceXz, OperationKind::Zero];
 
 #[derive(Debug)]
 struct CError(String);
@@ -21,12 +46,474 @@ impl std::fmt::Display for CError {
     }
 }
 
+#[derive(Debug)]
+pub enum PayloadError {
+    InvalidMagic,
+    /// The file starts with text, such as an HTML error page saved by a failed download.
+    TextNotPayload,
+    /// The header declares a manifest longer than what is left in the file.
+    TruncatedManifest { declared_len: u64, available: u64 },
+    /// The file given as an OTA zip is not a readable zip archive.
+    NotAZip { path: String, source: zip::result::ZipError },
+    /// The OTA zip has no `payload.bin` entry, e.g. a full-image or older OTA format.
+    PayloadNotFound { path: String },
+    /// A compressed operation's data could not be decoded, usually a corrupt download.
+    DecompressionFailed { op_index: usize, op_type: Type, source: io::Error },
+    /// Extracting with [`ExtractOptions::diagnostics`] found operations that wrote less than
+    /// their extents cover, and the image failed verification.
+    ShortOperations { partition: String, operations: Vec<ShortOperation> },
+    /// An operation that writes payload data has `data_length` 0, a malformed payload.
+    EmptyOperationData { op_index: usize, op_type: Type },
+    /// The xz decoder failed to decode a known-good stream, so this build or device can't
+    /// extract partitions that use `REPLACE_XZ`.
+    XzUnavailable { reason: String },
+    /// The image was written out of order into a sink that can't be read back, such as a
+    /// [`NullSink`], so its hash couldn't be checked.
+    Unverifiable { source: io::Error },
+}
+
+/// An operation that salvage mode could not apply, whose destination was zero-filled.
+#[derive(Clone, Debug)]
+pub struct SkippedOperation {
+    pub op_index: usize,
+    /// Raw operation type from the manifest, which may be one this build doesn't know.
+    pub op_type: i32,
+    pub extents: Vec<BlockRange>,
+}
+
+/// An operation that produced fewer bytes than its destination extents cover.
+#[derive(Clone, Debug)]
+pub struct ShortOperation {
+    pub op_index: usize,
+    pub op_type: Type,
+    pub expected: u64,
+    pub written: u64,
+}
+
+impl Error for PayloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PayloadError::DecompressionFailed { source, .. } => Some(source),
+            PayloadError::NotAZip { source, .. } => Some(source),
+            PayloadError::Unverifiable { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PayloadError::InvalidMagic => write!(f, "Invalid Payload magic"),
+            PayloadError::TextNotPayload => write!(
+                f,
+                "This doesn't look like a payload, it starts with text: it may be an error page or the wrong URL"
+            ),
+            PayloadError::TruncatedManifest { declared_len, available } => write!(
+                f,
+                "Truncated manifest: header declares {} bytes but only {} are available, the payload may be incomplete",
+                declared_len, available
+            ),
+            PayloadError::NotAZip { path, source } => write!(f, "{} is not a zip archive: {}", path, source),
+            PayloadError::PayloadNotFound { path } => write!(f, "/payload.bin not found inside zip {}", path),
+            PayloadError::DecompressionFailed { op_index, op_type, source } => write!(
+                f,
+                "Decompression failed in operation {} ({}): {}, the payload may be corrupt",
+                op_index, op_type.as_str_name(), source
+            ),
+            PayloadError::EmptyOperationData { op_index, op_type } => write!(
+                f,
+                "Operation {} ({}) has no data, the payload may be corrupt",
+                op_index, op_type.as_str_name()
+            ),
+            PayloadError::XzUnavailable { reason } => write!(f, "XZ decompression unavailable on this build/device: {}", reason),
+            PayloadError::Unverifiable { source } => write!(f, "output was written out of order and cannot be verified: {}", source),
+            PayloadError::ShortOperations { partition, operations } => {
+                write!(f, "Partition Hash mismatch error: {} has {} short operations", partition, operations.len())?;
+                for (i, op) in operations.iter().enumerate() {
+                    write!(
+                        f,
+                        "{} operation {} ({}) wrote {} of {} bytes",
+                        if i == 0 { ":" } else { "," }, op.op_index, op.op_type.as_str_name(), op.written, op.expected
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 pub struct Payload {
     path: String,
-    file: File,
+    /// `None` after [`Payload::release_handle`] until the next call that needs the file.
+    file: Option<File>,
+    zip_offset: u64,
+    /// File position just past the end of the payload, which for a zip is the end of the
+    /// `payload.bin` entry rather than the end of the file.
+    end: u64,
+    header: Option<Arc<PayloadHeader>>,
+    manifest: Option<Arc<DeltaArchiveManifest>>,
+    /// Copy made by [`Payload::from_stream`], shared with any [`SharedPayload`] made from it.
+    temp_copy: Option<Arc<TempCopy>>,
+    hash: Arc<dyn HashProvider>,
+}
+
+/// Parsed payload metadata that can be shared between threads. Each [`SharedPayload::open`]
+/// gets its own file handle around the same header and manifest, so several partitions can
+/// be extracted at once without parsing the payload again.
+#[derive(Clone)]
+pub struct SharedPayload {
+    path: String,
     zip_offset: u64,
-    header: Option<PayloadHeader>,
-    manifest: Option<DeltaArchiveManifest>,
+    end: u64,
+    header: Arc<PayloadHeader>,
+    manifest: Arc<DeltaArchiveManifest>,
+    temp_copy: Option<Arc<TempCopy>>,
+    hash: Arc<dyn HashProvider>,
+}
+
+/// Temporary file that is deleted once the last payload using it is dropped.
+struct TempCopy(PathBuf);
+
+/// Where `payload.bin` starts inside the file at a given path.
+///
+/// Resolving this for a zip means reading its central directory, which is slow for big
+/// OTA packages. Keep the value from [`Payload::location`] and hand it back to
+/// [`Payload::with_location`] to reopen the same file without scanning the zip again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PayloadLocation {
+    pub offset: u64,
+    /// Length of `payload.bin` in bytes.
+    pub size: u64,
+    pub compression: CompressionMethod,
+}
+
+/// Summary of a single partition from the manifest.
+#[derive(Clone, Debug)]
+pub struct PartitionInfo {
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+    pub operation_count: usize,
+    /// Sum of the operations' `data_length`, i.e. the compressed bytes read for this partition.
+    pub total_data_length: u64,
+    /// Whether every operation is one of [`supported_operations`], so the partition can be
+    /// extracted into a new image.
+    pub supported: bool,
+    /// Whether the image is a single run of uncompressed data in the payload, which is
+    /// extracted with a plain copy.
+    pub contiguous: bool,
+    /// Operation count and total `data_length` per operation type, in the order each type
+    /// first appears. Types this build doesn't know are left out.
+    pub operation_stats: Vec<(OperationKind, usize, u64)>,
+}
+
+impl PartitionInfo {
+    fn from_update(partition: &PartitionUpdate) -> PartitionInfo {
+        let info = partition.new_partition_info.as_ref();
+        PartitionInfo {
+            name: partition.partition_name.clone(),
+            size: info.and_then(|info| info.size).unwrap_or(0),
+            hash: hex::encode(info.and_then(|info| info.hash.as_ref()).map_or(&[][..], |hash| hash)),
+            operation_count: partition.operations.len(),
+            total_data_length: partition.operations.iter().map(|op| op.data_length.unwrap_or(0)).sum(),
+            supported: partition.operations.iter().all(operation_supported),
+            contiguous: is_contiguous(partition),
+            operation_stats: operation_stats(partition),
+        }
+    }
+}
+
+/// What [`Payload::preflight`] found for one partition.
+#[derive(Clone, Debug)]
+pub struct PreflightResult {
+    pub partition: String,
+    /// Index of the first operation this build can't extract, if any.
+    pub first_unsupported_op: Option<usize>,
+    /// Each unsupported operation type found, as its raw manifest value, in order of
+    /// first appearance.
+    pub unsupported_types: Vec<i32>,
+}
+
+impl PreflightResult {
+    pub fn supported(&self) -> bool {
+        self.unsupported_types.is_empty()
+    }
+
+    /// Names of the unsupported operation types, e.g. `BROTLI_BSDIFF`.
+    pub fn unsupported_names(&self) -> Vec<String> {
+        self.unsupported_types.iter().map(|&op_type| match Type::try_from(op_type) {
+            Ok(kind) => kind.as_str_name().into(),
+            Err(_) => format!("unknown ({})", op_type),
+        }).collect()
+    }
+}
+
+/// Copy-on-write estimates for a Virtual A/B partition, used to size snapshot space.
+#[derive(Clone, Debug)]
+pub struct CowInfo {
+    pub partition: String,
+    /// Estimated bytes of COW space the update needs.
+    pub estimate_cow_size: u64,
+    /// Upper bound on the number of COW operations, if the payload records it.
+    pub estimate_op_count_max: Option<u64>,
+}
+
+/// A run of blocks in a partition image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockRange {
+    pub start_block: u64,
+    pub num_blocks: u64,
+}
+
+impl BlockRange {
+    fn from_extent(extent: &Extent) -> BlockRange {
+        BlockRange { start_block: extent.start_block(), num_blocks: extent.num_blocks() }
+    }
+}
+
+/// dm-verity layout of a partition, as recorded in the manifest.
+///
+/// The update engine usually builds the hash tree and FEC data on the device after applying
+/// the operations, so no operation writes those blocks and they come out as zeros in an
+/// extracted image. `hash_tree_written` and `fec_written` tell whether operations cover
+/// them; when they don't, the image needs its verity metadata regenerated (e.g. with
+/// `avbtool`) before it will pass verity on a device.
+#[derive(Clone, Debug)]
+pub struct VerityInfo {
+    pub partition: String,
+    /// Blocks the hash tree is computed over.
+    pub hash_tree_data: Option<BlockRange>,
+    /// Where the hash tree is stored.
+    pub hash_tree: Option<BlockRange>,
+    pub hash_tree_algorithm: Option<String>,
+    /// Hex salt for the hash tree.
+    pub hash_tree_salt: Option<String>,
+    /// Blocks the FEC data protects.
+    pub fec_data: Option<BlockRange>,
+    /// Where the FEC data is stored.
+    pub fec: Option<BlockRange>,
+    pub fec_roots: Option<u32>,
+    pub hash_tree_written: bool,
+    pub fec_written: bool,
+}
+
+/// Partitions that share a base name, such as `boot_a` and `boot_b` under `boot`.
+#[derive(Clone, Debug)]
+pub struct SlotGroup {
+    pub base_name: String,
+    /// Each partition with its slot letter, or `None` for a name without a slot suffix.
+    pub slots: Vec<(Option<char>, PartitionInfo)>,
+}
+
+/// Splits an A/B slot suffix off a partition name: `boot_a` gives `("boot", Some('a'))`,
+/// while `vendor_boot` is left whole.
+pub fn split_slot(name: &str) -> (&str, Option<char>) {
+    for (suffix, slot) in [("_a", 'a'), ("_b", 'b')] {
+        match name.strip_suffix(suffix) {
+            Some(base) if !base.is_empty() => return (base, Some(slot)),
+            _ => {}
+        }
+    }
+    (name, None)
+}
+
+/// Partitions that [`Payload::extract_firmware`] leaves out unless told otherwise.
+pub const DEFAULT_EXCLUDED_PARTITIONS: [&str; 3] = ["userdata", "cache", "metadata"];
+
+/// Outcome of [`Payload::extract_firmware`].
+#[derive(Clone, Debug)]
+pub struct FirmwareExtraction {
+    pub results: Vec<ExtractResult>,
+    /// Partitions left out by the exclusion list, in manifest order.
+    pub skipped: Vec<String>,
+}
+
+/// Order in which a batch extraction visits the requested partitions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExtractOrder {
+    /// The order the partitions appear in the manifest.
+    #[default]
+    Manifest,
+    LargestFirst,
+    SmallestFirst,
+}
+
+/// File format of extracted partition images.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Raw,
+    /// Android sparse image. ZERO operations are stored as zero FILL chunks and blocks no
+    /// operation writes as DONT_CARE chunks.
+    Sparse,
+}
+
+/// What to do when an output path is a symlink.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Fail rather than write through the link.
+    #[default]
+    Refuse,
+    /// Write to the file the link points at.
+    Follow,
+}
+
+/// Which hashes extraction checks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashGranularity {
+    /// Only the hash of the whole image, skipping the per-operation data hashes.
+    PartitionOnly,
+    /// Only the per-operation data hashes, skipping the hash of the whole image.
+    PerOperation,
+    /// The per-operation data hashes where present, and the hash of the whole image.
+    #[default]
+    Both,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    pub order: ExtractOrder,
+    pub format: OutputFormat,
+    /// Write into the existing output file instead of truncating it, resizing it to the
+    /// partition size first. Meant for preallocated images that are updated in place.
+    ///
+    /// The existing content is treated as the source image, which lets `SOURCE_COPY`
+    /// operations be applied; ones whose source and destination extents match are skipped.
+    /// Operations run in manifest order, so this is only correct for payloads where no
+    /// operation reads blocks that an earlier one already overwrote.
+    ///
+    /// The output may also be a block device, such as the inactive slot's partition.
+    pub in_place: bool,
+    /// Also compute a CRC32 of each extracted image, in the same pass as the SHA256 check.
+    pub crc32: bool,
+    /// After a batch extraction, write an `extraction.json` summary into the output directory.
+    pub summary: bool,
+    /// When an image has to be read back for verification, hash a memory map of the file
+    /// instead of using buffered reads. Falls back to buffered reads if mapping fails, e.g.
+    /// on filesystems without mmap support or images larger than the address space.
+    ///
+    /// Images are normally hashed while they are written, so this only matters for the rare
+    /// partitions written out of order. It is off by default: the two ways of reading back
+    /// haven't been benchmarked against each other.
+    pub mmap_verify: bool,
+    /// Stop a batch extraction at the first partition that fails. By default the failure is
+    /// recorded in that partition's [`ExtractResult`] and the batch moves on to the next one.
+    pub strict: bool,
+    /// Debugging aid for truncated images: instead of failing on the first operation that
+    /// writes fewer bytes than expected, keep going and report every such operation, see
+    /// [`PayloadError::ShortOperations`] and [`ExtractResult::short_operations`].
+    pub diagnostics: bool,
+    /// How to treat output paths that are symlinks. Either way an existing output that is
+    /// not a regular file, such as a directory or device node, is refused, except for block
+    /// devices written with [`ExtractOptions::in_place`].
+    pub symlinks: SymlinkPolicy,
+    /// Best effort for partially supported payloads: zero-fill the blocks of operations this
+    /// build can't apply instead of failing. Such images fail the partition hash, so they
+    /// are returned as [`ExtractResult::partial`] rather than as an error.
+    pub salvage: bool,
+    /// Rough cap, in bytes, on the buffers extraction allocates, for low-memory devices.
+    /// Operation data larger than a quarter of it is streamed from the payload instead of
+    /// being loaded whole, and read buffers are sized to fit. In-place `SOURCE_COPY` reads
+    /// its source in chunks too, unless the source and destination blocks overlap. Decoder
+    /// state such as the xz dictionary is set by the payload and isn't covered.
+    pub max_memory: Option<u64>,
+    pub hash_granularity: HashGranularity,
+}
+
+/// Outcome of extracting one partition.
+#[derive(Clone, Debug)]
+pub struct ExtractResult {
+    pub partition: String,
+    pub out_file: String,
+    pub size: u64,
+    pub expected_hash: String,
+    pub computed_hash: String,
+    pub crc32: Option<u32>,
+    /// Why the partition could not be extracted or verified, for failures recorded by a
+    /// batch extraction. `computed_hash` is empty in that case.
+    pub error: Option<String>,
+    /// Operations that came up short, recorded with [`ExtractOptions::diagnostics`].
+    pub short_operations: Vec<ShortOperation>,
+    /// Unsupported operations that [`ExtractOptions::salvage`] zero-filled instead.
+    pub skipped_operations: Vec<SkippedOperation>,
+    /// The image decoded without errors but couldn't be hashed, see
+    /// [`PayloadError::Unverifiable`]. Not verified, but not known to be bad either.
+    pub unverifiable: bool,
+}
+
+impl ExtractResult {
+    /// Whether the image was written without errors and its hash matches. With
+    /// [`HashGranularity::PerOperation`] the image hash isn't computed, `computed_hash` is
+    /// empty and only the operation hashes count.
+    pub fn verified(&self) -> bool {
+        self.error.is_none() && if self.computed_hash.is_empty() { !self.partial() } else { self.expected_hash == self.computed_hash }
+    }
+
+    /// A best-effort image from salvage mode, with some operations zero-filled. It is
+    /// unverified unless the hash happened to match anyway.
+    pub fn partial(&self) -> bool {
+        !self.skipped_operations.is_empty()
+    }
+
+    fn failed(partition: &PartitionUpdate, out_file: &str, err: &(dyn Error + 'static)) -> ExtractResult {
+        let info = partition.new_partition_info.as_ref();
+        ExtractResult {
+            partition: partition.partition_name.clone(),
+            out_file: out_file.into(),
+            size: info.and_then(|info| info.size).unwrap_or(0),
+            expected_hash: info.and_then(|info| info.hash.as_ref()).map(hex::encode).unwrap_or_default(),
+            computed_hash: String::new(),
+            crc32: None,
+            error: Some(err.to_string()),
+            short_operations: match err.downcast_ref::<PayloadError>() {
+                Some(PayloadError::ShortOperations { operations, .. }) => operations.clone(),
+                _ => Vec::new(),
+            },
+            skipped_operations: Vec::new(),
+            unverifiable: matches!(err.downcast_ref::<PayloadError>(), Some(PayloadError::Unverifiable { .. })),
+        }
+    }
+}
+
+/// How a partition's manifest hash compares with an expected hash list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyStatus {
+    Match,
+    Mismatch,
+    /// Listed in the expected hashes but not in the payload.
+    MissingFromPayload,
+    /// In the payload but not in the expected hashes.
+    MissingFromList,
+    /// Listed in the sums file given to [`Payload::verify_output_dir`] but not found in the
+    /// directory.
+    MissingFile,
+}
+
+/// One entry from [`Payload::verify_against`] or [`Payload::verify_output_dir`].
+#[derive(Clone, Debug)]
+pub struct VerifyResult {
+    pub partition: String,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+    pub status: VerifyStatus,
+}
+
+/// Signatures carried by a payload, from [`Payload::signatures`]. Each entry is the raw
+/// signature, cut to its unpadded size where the payload records one.
+#[derive(Clone, Debug, Default)]
+pub struct PayloadSignatures {
+    /// Signatures over the header and manifest. Empty when the header's `signature_len` is 0.
+    pub metadata: Vec<Vec<u8>>,
+    /// Signatures over the whole payload, stored at the end of the data region.
+    pub payload: Vec<Vec<u8>>,
+}
+
+impl PayloadSignatures {
+    /// Whether the payload is unsigned, as development builds and self-made OTAs often are.
+    pub fn unsigned(&self) -> bool {
+        self.metadata.is_empty() && self.payload.is_empty()
+    }
 }
 
 pub struct PayloadHeader {
@@ -46,33 +533,160 @@ impl Payload {
                 return Err(format!("Err: {}", err).into());
             }
         };
-        let mut offset: u64 = 0;
-        if path.ends_with(".zip") {
-            let mut archive = ZipArchive::new(&mut file)?;
-            offset = archive.by_name("payload.bin").or(Err("/payload.bin not found inside zip"))?.data_start();
+        let location = if path.ends_with(".zip") {
+            zip_location(&mut file, &path)?
+        } else {
+            PayloadLocation { offset: 0, size: file.metadata()?.len(), compression: CompressionMethod::Stored }
+        };
+        Payload::open(path, file, location)
+    }
+
+    /// Opens `path` using a location saved from an earlier [`Payload::location`] call,
+    /// skipping the zip directory scan.
+    pub fn with_location(path: String, location: PayloadLocation) -> Result<Payload, Box<dyn Error>> {
+        let file = match File::open(path.clone()) {
+            Ok(f) => f,
+            Err(err) => {
+                return Err(format!("Err: {}", err).into());
+            }
+        };
+        Payload::open(path, file, location)
+    }
+
+    fn open(path: String, file: File, location: PayloadLocation) -> Result<Payload, Box<dyn Error>> {
+        if location.compression != CompressionMethod::Stored {
+            return Err(format!("payload.bin is compressed inside zip ({}), only stored entries are supported", location.compression).into());
+        }
+        let end = location.offset.checked_add(location.size).ok_or("payload location is out of range")?;
+        // A saved location may be stale, checked the same way as in `reopen`.
+        if file.metadata()?.len() < end {
+            return Err(format!("{} changed since it was opened, open it again", path).into());
         }
         Ok(Payload {
             path,
-            file,
-            zip_offset: offset,
+            file: Some(file),
+            zip_offset: location.offset,
+            end,
             header: None,
             manifest: None,
+            temp_copy: None,
+            hash: Arc::new(SoftwareHash),
         })
     }
 
+    /// Opens a payload from a forward-only stream, such as a content URI opened with
+    /// `openInputStream`. Extraction needs to seek to each operation's data, so the whole
+    /// stream is first copied into a temporary file under `scratch_dir` and parsed from there.
+    /// Without a `scratch_dir` the system temp directory is used, which on Android is often
+    /// small internal storage, so apps should pass a directory of their own.
+    ///
+    /// This needs free space in `scratch_dir` for the full payload (or OTA zip) on top of the
+    /// extracted images. When `stream_len` is known it is checked up front. The copy is
+    /// deleted when the returned `Payload` is dropped.
+    pub fn from_stream(reader: &mut dyn Read, scratch_dir: Option<&str>, stream_len: Option<u64>) -> Result<Payload, Box<dyn Error>> {
+        let scratch_dir = scratch_dir.map_or_else(std::env::temp_dir, PathBuf::from);
+        check_scratch_dir(&scratch_dir, stream_len.unwrap_or(0))?;
+
+        let mut magic = [0u8; 4];
+        let mut read = 0;
+        while read < magic.len() {
+            match reader.read(&mut magic[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(format!("failed to read payload stream: {}", err).into()),
+            }
+        }
+        // Payload::new tells a zip apart by its extension, so name the copy accordingly.
+        let extension = if &magic[..read] == b"PK\x03\x04" { "zip" } else { "bin" };
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+        let temp_path = scratch_dir.join(format!("payload-{}-{}.{}", std::process::id(), nanos, extension));
+
+        let copied = File::create(&temp_path).and_then(|mut file| {
+            file.write_all(&magic[..read])?;
+            io::copy(reader, &mut file)?;
+            file.sync_all()
+        });
+        if let Err(err) = copied {
+            let _ = fs::remove_file(&temp_path);
+            return Err(format!("failed to copy payload stream to {}: {}", temp_path.display(), err).into());
+        }
+
+        match Payload::new(temp_path.to_string_lossy().into()) {
+            Ok(mut payload) => {
+                payload.temp_copy = Some(Arc::new(TempCopy(temp_path)));
+                Ok(payload)
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&temp_path);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn location(&self) -> PayloadLocation {
+        PayloadLocation { offset: self.zip_offset, size: self.end.saturating_sub(self.zip_offset), compression: CompressionMethod::Stored }
+    }
+
+    /// Replaces the SHA-256 implementation used to verify partitions and operations, e.g.
+    /// with a hardware-backed one. Defaults to [`SoftwareHash`].
+    pub fn set_hash_provider(&mut self, provider: Arc<dyn HashProvider>) {
+        self.hash = provider;
+    }
+
+    /// Closes the payload file while keeping the parsed header and manifest, so the source
+    /// can be moved or deleted between extractions. The next call that reads the payload
+    /// opens the file again.
+    pub fn release_handle(&mut self) {
+        self.file = None;
+    }
+
+    fn reopen(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.file.is_some() {
+            return Ok(());
+        }
+        let file = File::open(&self.path).map_err(|err| format!("failed to reopen {}: {}", self.path, err))?;
+        if file.metadata()?.len() < self.end {
+            return Err(format!("{} changed since it was opened, open it again", self.path).into());
+        }
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn file(&self) -> Result<&File, Box<dyn Error>> {
+        self.file.as_ref().ok_or_else(|| format!("{} is not open", self.path).into())
+    }
+
     fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.reopen()?;
+        if self.header.is_some() && self.manifest.is_some() {
+            return Ok(());
+        }
 
-        let _ = self.file.seek(SeekFrom::Start(self.zip_offset))?;
+        let _ = self.file()?.seek(SeekFrom::Start(self.zip_offset))?;
 
         match self.read_header() {
-            Ok(header) => self.header = Some(header),
+            Ok(header) => self.header = Some(Arc::new(header)),
             Err(err) => {
-                return Err(err);
+                // Some repackaged payloads have a little padding or a wrapper before the magic.
+                let skip = match err.downcast_ref::<PayloadError>() {
+                    Some(PayloadError::InvalidMagic) => self.find_magic()?,
+                    _ => None,
+                };
+                let Some(skip) = skip else {
+                    if matches!(err.downcast_ref::<PayloadError>(), Some(PayloadError::InvalidMagic)) && self.starts_with_text()? {
+                        return Err(PayloadError::TextNotPayload.into());
+                    }
+                    return Err(err);
+                };
+                self.zip_offset += skip;
+                self.file()?.seek(SeekFrom::Start(self.zip_offset))?;
+                self.header = Some(Arc::new(self.read_header()?));
             }
         }
 
         match self.read_manifest() {
-            Ok(manifest) => self.manifest = Some(manifest),
+            Ok(manifest) => self.manifest = Some(Arc::new(manifest)),
             Err(err) => {
                 return Err(err);
             }
@@ -80,13 +694,48 @@ impl Payload {
         Ok(())
     }
 
+    /// The manifest, parsing the payload first if needed. It comes as a shared handle so it
+    /// can be held across calls that borrow the payload mutably, like extraction.
+    fn manifest(&mut self) -> Result<Arc<DeltaArchiveManifest>, Box<dyn Error>> {
+        self.init()?;
+        Ok(self.manifest.clone().ok_or(Box::new(CError("manifest not found".into())))?)
+    }
+
+    /// Fails with every name from `names` that is not in the manifest.
+    fn check_known(&mut self, names: &[String]) -> Result<(), Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let missing = unknown_partitions(&manifest, names);
+        if !missing.is_empty() {
+            return Err(format!("partition: {} not found in {}", missing.join(", "), &self.path).into());
+        }
+        Ok(())
+    }
+
+    /// Offset of the magic within the first `MAGIC_SCAN_LIMIT` bytes from `zip_offset`.
+    fn find_magic(&mut self) -> Result<Option<u64>, Box<dyn Error>> {
+        self.file()?.seek(SeekFrom::Start(self.zip_offset))?;
+        let mut buf = Vec::new();
+        Read::take(self.file()?, MAGIC_SCAN_LIMIT).read_to_end(&mut buf)?;
+        Ok(buf.windows(PAYLOAD_HEADER_MAGIC.len()).position(|w| w == PAYLOAD_HEADER_MAGIC.as_bytes()).map(|pos| pos as u64))
+    }
+
+    /// Whether the file opens with text rather than binary data: no control bytes other
+    /// than whitespace in the first `TEXT_PROBE_LEN` bytes, after any UTF-8 BOM.
+    fn starts_with_text(&mut self) -> Result<bool, Box<dyn Error>> {
+        self.file()?.seek(SeekFrom::Start(self.zip_offset))?;
+        let mut buf = Vec::new();
+        Read::take(self.file()?, TEXT_PROBE_LEN).read_to_end(&mut buf)?;
+        let text = buf.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&buf);
+        Ok(!text.is_empty() && text.iter().all(|&b| matches!(b, b'\t' | b'\n' | b'\r') || (b >= 0x20 && b != 0x7f)))
+    }
+
     fn read_header(&mut self) -> Result<PayloadHeader, Box<dyn Error>> {
         let mut buf = [0; 4];
 
-        self.file.read_exact(&mut buf)?;
+        self.file()?.read_exact(&mut buf)?;
 
-        if str::from_utf8(&buf)? != PAYLOAD_HEADER_MAGIC {
-            return Err("Invalid Payload magic".into());
+        if buf != PAYLOAD_HEADER_MAGIC.as_bytes() {
+            return Err(PayloadError::InvalidMagic.into());
         }
         let mut header = PayloadHeader {
             version: 0,
@@ -98,7 +747,7 @@ impl Payload {
         };
 
         let mut buf = [0; 8];
-        self.file.read_exact(&mut buf)?;
+        self.file()?.read_exact(&mut buf)?;
         header.version = u64::from_be_bytes(buf);
 
         if header.version != BRILLO_MAJOR_PAYLOAD_VERSION {
@@ -106,11 +755,11 @@ impl Payload {
         }
 
         let mut buf = [0; 8];
-        self.file.read_exact(&mut buf)?;
+        self.file()?.read_exact(&mut buf)?;
         header.manifest_len = u64::from_be_bytes(buf);
 
         let mut buf = [0; 4];
-        self.file.read_exact(&mut buf)?;
+        self.file()?.read_exact(&mut buf)?;
         header.signature_len = u32::from_be_bytes(buf);
 
         header.size = 24;
@@ -120,144 +769,866 @@ impl Payload {
         Ok(header)
     }
 
+    /// Absolute file position of `relative_offset` bytes into the data region. All
+    /// partitions share that region, so every operation read goes through here.
+    fn data_position(&self, relative_offset: u64) -> Result<u64, Box<dyn Error>> {
+        let header = self.header.as_ref().ok_or(Box::new(CError("header not found".into())))?;
+        self.zip_offset
+            .checked_add(header.data_offset)
+            .and_then(|base| base.checked_add(relative_offset))
+            .ok_or_else(|| format!("data offset {} is out of range", relative_offset).into())
+    }
+
+    /// Reads `len` bytes at `relative_offset` into the data region, i.e. the blob an
+    /// operation with that `data_offset` and `data_length` points at.
+    pub fn read_data(&mut self, relative_offset: u64, len: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.init()?;
+        self.read_range(relative_offset, len)
+    }
+
+    /// Size of the data region, from `data_offset` to the end of the payload. Operation
+    /// blobs and the signatures blob live here, so comparing this with the bytes they
+    /// account for shows trailing garbage or a truncated download.
+    pub fn data_region_size(&mut self) -> Result<u64, Box<dyn Error>> {
+        self.init()?;
+        let start = self.data_position(0)?;
+        Ok(self.end.saturating_sub(start))
+    }
+
+    /// A SHA-256 hasher if `needed`, otherwise one that does no work.
+    fn hasher_if(&self, needed: bool) -> Box<dyn Sha256Hasher> {
+        if needed {
+            self.hash.sha256()
+        } else {
+            Box::new(NoHash)
+        }
+    }
+
+    fn read_range(&self, relative_offset: u64, len: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = Vec::new();
+        self.read_range_into(relative_offset, len, &mut data)?;
+        Ok(data)
+    }
+
+    /// Like `read_range`, but reuses `data`'s allocation, which only grows.
+    fn read_range_into(&self, relative_offset: u64, len: u64, data: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let mut reader = self.data_reader(relative_offset, len)?;
+        data.resize(len as usize, 0);
+        reader.read_exact(data)?;
+        Ok(())
+    }
+
+    /// Reader over `len` bytes at `relative_offset` into the data region. Tests can make
+    /// it fail partway, see `tests::inject_read_fault`.
+    fn data_reader(&self, relative_offset: u64, len: u64) -> Result<impl Read + '_, Box<dyn Error>> {
+        let start = self.data_position(relative_offset)?;
+        if start.checked_add(len).is_none_or(|end| end > self.end) {
+            return Err(format!("reading {} bytes at data offset {} runs past the end of the payload", len, relative_offset).into());
+        }
+        let mut file = self.file()?;
+        file.seek(SeekFrom::Start(start))?;
+        let reader = Read::take(file, len);
+        #[cfg(test)]
+        let reader = tests::FaultyReader(reader);
+        Ok(reader)
+    }
+
     fn read_manifest(&mut self) -> Result<DeltaArchiveManifest, Box<dyn Error>> {
-        let manifest_len = self.header.as_ref().ok_or(Box::new(CError("header not found".into())))?.manifest_len as usize;
-        let mut manifest_buf = vec![0; manifest_len];
+        let manifest_len = self.header.as_ref().ok_or(Box::new(CError("header not found".into())))?.manifest_len;
 
-        self.file.read_exact(&mut manifest_buf)?;
+        // Bounded by the end of the payload, not the file: in a zip the central directory
+        // follows it and would otherwise hide a truncated manifest.
+        let available = self.end.saturating_sub(self.file()?.stream_position()?);
+        if manifest_len > available {
+            return Err(PayloadError::TruncatedManifest { declared_len: manifest_len, available }.into());
+        }
+        let mut manifest_buf = vec![0; manifest_len as usize];
+
+        self.file()?.read_exact(&mut manifest_buf)?;
 
         let delta_manifest: DeltaArchiveManifest = prost::Message::decode(&manifest_buf[..])?;
 
         Ok(delta_manifest)
     }
 
+    /// The manifest exactly as stored in the payload, an undecoded `DeltaArchiveManifest`
+    /// protobuf for tools such as `protoc --decode`.
+    pub fn raw_manifest_bytes(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.init()?;
+
+        let header = self.header.as_ref().ok_or(Box::new(CError("header not found".into())))?;
+        let (offset, manifest_len) = (self.zip_offset + header.size, header.manifest_len);
+        self.file()?.seek(SeekFrom::Start(offset))?;
+        let mut manifest_buf = vec![0; manifest_len as usize];
+        self.file()?.read_exact(&mut manifest_buf)?;
+        Ok(manifest_buf)
+    }
+
     pub fn extract<'p>(&mut self, partition_to_extract: &str, out_file: &str, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<String, Box<dyn Error>> {
-        if let Err(err) = self.init() {
-            return Err(err);
+        self.extract_with(partition_to_extract, out_file, &ExtractOptions::default(), onprogress, onverify)
+    }
+
+    pub fn extract_with<'p>(&mut self, partition_to_extract: &str, out_file: &str, options: &ExtractOptions, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<String, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let mut partition: Option<&PartitionUpdate> = None;
+        let mut skipped = 0;
+        for p in manifest.partitions.iter() {
+            if partition_to_extract == p.partition_name {
+                partition = Some(p);
+                skipped += self.extract_selected(p, out_file, options, onprogress, onverify)?.skipped_operations.len();
+            };
+        }
+        if partition.is_none() {
+            return Err(format!("partition: {} not found in {}", partition_to_extract, &self.path).into());
+        }
+        if skipped > 0 {
+            return Ok(format!("Partial: {} unsupported operations zero-filled, image is unverified", skipped));
         }
 
-        if let Some(manifest) = &self.manifest {
-            let mut partition: Option<&PartitionUpdate> = None;
-            let partitions = manifest.partitions.clone();
-            for (_, p) in partitions.iter().enumerate() {
-                if partition_to_extract == p.partition_name {
-                    partition = Some(p);
-                    if let Err(err) = self.extract_selected(p, out_file, &onprogress, &onverify) {
+        Ok("Done".into())
+    }
+
+    /// Extracts the partition at `index` in manifest order and returns its name.
+    pub fn extract_by_index<'p>(&mut self, index: usize, out_file: &str, options: &ExtractOptions, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<String, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let partition = manifest.partitions.get(index).ok_or_else(|| {
+            format!("partition index {} is out of range, {} has {} partitions", index, &self.path, manifest.partitions.len())
+        })?;
+        self.extract_selected(partition, out_file, options, onprogress, onverify)?;
+        Ok(partition.partition_name.clone())
+    }
+
+    /// Returns the names from `partitions` that are not in the manifest.
+    pub fn unknown_partitions(&mut self, partitions: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        Ok(unknown_partitions(&manifest, partitions))
+    }
+
+    /// Extracts several partitions into `out_dir` as `<name>.img`, or every partition when
+    /// `partitions` is empty. `oncomplete` fires after each partition is written and verified.
+    ///
+    /// A partition that fails is reported through its result's `error`, and the remaining
+    /// ones are still extracted, unless [`ExtractOptions::strict`] is set.
+    pub fn extract_partitions<'p>(&mut self, partitions: &[String], out_dir: &str, options: &ExtractOptions, onprogress: &'p dyn Fn(&str, usize), onverify: &'p dyn Fn(&str, i8), oncomplete: &'p dyn Fn(&ExtractResult)) -> Result<Vec<ExtractResult>, Box<dyn Error>> {
+        let selected = self.select_partitions(partitions, options)?;
+
+        let mut results = Vec::with_capacity(selected.len());
+        for partition in &selected {
+            let name = partition.partition_name.as_str();
+            let out_file = Path::new(out_dir).join(format!("{}.img", name));
+            let out_file = out_file.to_str().ok_or(Box::new(CError("invalid output path".into())))?;
+            let result = match self.extract_selected(partition, out_file, options, &|progress| onprogress(name, progress), &|status| onverify(name, status)) {
+                Ok(result) => result,
+                Err(err) if options.strict => return Err(err),
+                Err(err) => ExtractResult::failed(partition, out_file, err.as_ref()),
+            };
+            oncomplete(&result);
+            results.push(result);
+        }
+        if options.summary {
+            let summary_path = Path::new(out_dir).join("extraction.json");
+            check_output_path(&summary_path.to_string_lossy(), options.symlinks, false)?;
+            fs::write(&summary_path, summary_json(&results))?;
+        }
+        Ok(results)
+    }
+
+    /// Like [`Payload::extract_partitions`], but streams each image straight into an entry
+    /// of a new zip archive at `zip_path` rather than into loose files, so no extra space is
+    /// needed to archive them afterwards. Images are hashed on their way into the archive.
+    /// Only raw images can be written this way, and partitions must be written in order;
+    /// any that aren't are reported as failed and left out of the archive.
+    pub fn extract_to_zip<'p>(&mut self, partitions: &[String], zip_path: &str, options: &ExtractOptions, onprogress: &'p dyn Fn(&str, usize), onverify: &'p dyn Fn(&str, i8), oncomplete: &'p dyn Fn(&ExtractResult)) -> Result<Vec<ExtractResult>, Box<dyn Error>> {
+        if options.format != OutputFormat::Raw || options.in_place {
+            return Err("zip output only supports raw images written from scratch".into());
+        }
+        let selected = self.select_partitions(partitions, options)?;
+        check_output_path(zip_path, options.symlinks, false)?;
+        if same_file(Path::new(zip_path), Path::new(&self.path)) {
+            return Err(format!("output {} is the payload being extracted, choose another path", zip_path).into());
+        }
+        let mut zip = match File::create(zip_path) {
+            Ok(file) => ZipWriter::new(file),
+            Err(err) => {
+                return Err(format!("file create error: {}", err).into());
+            }
+        };
+
+        let mut results = Vec::with_capacity(selected.len());
+        for partition in &selected {
+            let name = partition.partition_name.as_str();
+            let entry_name = format!("{}.img", name);
+            let size = partition.new_partition_info.as_ref().and_then(|info| info.size).unwrap_or(0);
+            let entry_options = SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Deflated)
+                .large_file(size >= u32::MAX as u64);
+            zip.start_file(entry_name.as_str(), entry_options)?;
+            let mut sink = StreamingSink::new(&mut zip, size);
+            let written = self.write_partition(partition, &mut sink, options, &|progress| onprogress(name, progress), &|status| onverify(name, status));
+            let result = match written {
+                Ok(mut result) => {
+                    result.out_file = entry_name;
+                    result
+                }
+                Err(err) => {
+                    zip.abort_file()?;
+                    if options.strict {
                         return Err(err);
                     }
-                };
+                    ExtractResult::failed(partition, &entry_name, err.as_ref())
+                }
+            };
+            oncomplete(&result);
+            results.push(result);
+        }
+        if options.summary {
+            zip.start_file("extraction.json", SimpleFileOptions::default())?;
+            zip.write_all(summary_json(&results).as_bytes())?;
+        }
+        zip.finish()?;
+        Ok(results)
+    }
+
+    /// The partitions from `partitions` (every partition if empty) in `options.order`.
+    fn select_partitions(&mut self, partitions: &[String], options: &ExtractOptions) -> Result<Vec<PartitionUpdate>, Box<dyn Error>> {
+        self.check_known(partitions)?;
+        let manifest = self.manifest()?;
+        let mut selected: Vec<PartitionUpdate> = if partitions.is_empty() {
+            manifest.partitions.clone()
+        } else {
+            manifest.partitions.iter().filter(|p| partitions.contains(&p.partition_name)).cloned().collect()
+        };
+
+        let size_of = |p: &PartitionUpdate| p.new_partition_info.as_ref().and_then(|info| info.size).unwrap_or(0);
+        match options.order {
+            ExtractOrder::Manifest => {}
+            ExtractOrder::LargestFirst => selected.sort_by_key(|p| std::cmp::Reverse(size_of(p))),
+            ExtractOrder::SmallestFirst => selected.sort_by_key(size_of),
+        }
+        Ok(selected)
+    }
+
+    /// Checks every partition's hash by decoding it into a [`NullSink`], without writing any
+    /// files. Failures are recorded in the results like a non-strict batch extraction.
+    ///
+    /// Images have to come out in order to be hashed on the fly, which full OTAs always do.
+    /// A partition whose operations jump backwards can only be checked by extracting it, and
+    /// is reported as [`ExtractResult::unverifiable`] rather than as a hash mismatch.
+    pub fn verify_all<'p>(&mut self, onprogress: &'p dyn Fn(&str, usize), onverify: &'p dyn Fn(&str, i8), oncomplete: &'p dyn Fn(&ExtractResult)) -> Result<Vec<ExtractResult>, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let mut results = Vec::with_capacity(manifest.partitions.len());
+        for partition in &manifest.partitions {
+            let name = partition.partition_name.as_str();
+            let result = self.write_partition(partition, &mut NullSink, &ExtractOptions::default(), &|progress| onprogress(name, progress), &|status| onverify(name, status))
+                .unwrap_or_else(|err| ExtractResult::failed(partition, "", err.as_ref()));
+            oncomplete(&result);
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Extracts every partition in a dynamic partition group, e.g. `google_dynamic_partitions`.
+    pub fn extract_group<'p>(&mut self, group_name: &str, out_dir: &str, options: &ExtractOptions, onprogress: &'p dyn Fn(&str, usize), onverify: &'p dyn Fn(&str, i8), oncomplete: &'p dyn Fn(&ExtractResult)) -> Result<Vec<ExtractResult>, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let group = manifest.dynamic_partition_metadata.as_ref()
+            .and_then(|metadata| metadata.groups.iter().find(|group| group.name == group_name))
+            .ok_or_else(|| format!("group: {} not found in {}", group_name, &self.path))?;
+        if group.partition_names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let partitions = group.partition_names.clone();
+        self.extract_partitions(&partitions, out_dir, options, onprogress, onverify, oncomplete)
+    }
+
+    /// Extracts whichever of the partitions usually needed for rooting exist (`boot`,
+    /// `init_boot`, `dtbo` and `vbmeta`) and writes a `README.txt` into `out_dir` listing
+    /// what was found along with each image's hash.
+    pub fn export_patch_pack<'p>(&mut self, out_dir: &str, options: &ExtractOptions, onprogress: &'p dyn Fn(&str, usize), onverify: &'p dyn Fn(&str, i8), oncomplete: &'p dyn Fn(&ExtractResult)) -> Result<Vec<ExtractResult>, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let partitions: Vec<String> = PATCH_PACK_PARTITIONS.iter()
+            .filter(|name| manifest.partitions.iter().any(|p| p.partition_name == **name))
+            .map(|name| name.to_string())
+            .collect();
+        if partitions.is_empty() {
+            return Err(format!("none of {} found in {}", PATCH_PACK_PARTITIONS.join(", "), &self.path).into());
+        }
+        let results = self.extract_partitions(&partitions, out_dir, options, onprogress, onverify, oncomplete)?;
+        let readme_path = Path::new(out_dir).join("README.txt");
+        check_output_path(&readme_path.to_string_lossy(), options.symlinks, false)?;
+        write_patch_pack_readme(&readme_path, &results)?;
+        Ok(results)
+    }
+
+    /// Extracts every partition except bulky data ones that firmware flashing never needs.
+    /// `exclude` lists the base names to leave out, defaulting to
+    /// [`DEFAULT_EXCLUDED_PARTITIONS`]; slot suffixes are ignored when matching.
+    pub fn extract_firmware<'p>(&mut self, out_dir: &str, exclude: Option<&[String]>, options: &ExtractOptions, onprogress: &'p dyn Fn(&str, usize), onverify: &'p dyn Fn(&str, i8), oncomplete: &'p dyn Fn(&ExtractResult)) -> Result<FirmwareExtraction, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let excluded = |name: &str| {
+            let (base, _) = split_slot(name);
+            match exclude {
+                Some(exclude) => exclude.iter().any(|e| e == base),
+                None => DEFAULT_EXCLUDED_PARTITIONS.contains(&base),
             }
-            if partition.is_none() {
-                return Err(format!("partition: {} not found in {}", partition_to_extract, &self.path).into());
+        };
+        let (skipped, partitions): (Vec<String>, Vec<String>) = manifest.partitions.iter()
+            .map(|p| p.partition_name.clone())
+            .partition(|name| excluded(name));
+        if partitions.is_empty() {
+            return Ok(FirmwareExtraction { results: Vec::new(), skipped });
+        }
+        let results = self.extract_partitions(&partitions, out_dir, options, onprogress, onverify, oncomplete)?;
+        Ok(FirmwareExtraction { results, skipped })
+    }
+
+    /// Gives a ballpark of how long extracting `names` (every partition if empty) into
+    /// `out_dir` will take. One operation per codec is decompressed and timed, and a few
+    /// MiB are written to `out_dir` to gauge storage speed; those rates are then scaled up
+    /// to the selected partitions' sizes.
+    pub fn estimate_duration(&mut self, names: &[String], out_dir: &str) -> Result<Duration, Box<dyn Error>> {
+        self.check_known(names)?;
+        let manifest = self.manifest()?;
+        let operations: Vec<&InstallOperation> = manifest.partitions.iter()
+            .filter(|p| names.is_empty() || names.contains(&p.partition_name))
+            .flat_map(|p| &p.operations)
+            .collect();
+        let output_len = |op: &InstallOperation| -> u64 { op.dst_extents.iter().map(|e| e.num_blocks() * BLOCK_SIZE).sum() };
+
+        let mut seconds = 0.0;
+        for kind in [Type::ReplaceBz, Type::ReplaceXz] {
+            let of_kind = || operations.iter().filter(|op| op.r#type() == kind);
+            // Prefer the largest operation under the limit, so the sample is quick to decode
+            // but not so small that the timing is mostly noise.
+            let Some(sample) = of_kind().min_by_key(|op| {
+                let len = op.data_length.unwrap_or(0);
+                if len <= ESTIMATE_SAMPLE_LIMIT { (0, ESTIMATE_SAMPLE_LIMIT - len) } else { (1, len) }
+            }) else {
+                continue;
+            };
+
+            let start = Instant::now();
+            let data = self.read_range(sample.data_offset.unwrap_or(0), sample.data_length.unwrap_or(0))?;
+            let decoded = match kind {
+                Type::ReplaceBz => copy_decoded(&mut BzDecoder::new(data.as_slice()), &mut io::sink(), 0, kind)?,
+                _ => copy_decoded(&mut XzDecoder::new(data.as_slice()), &mut io::sink(), 0, kind)?,
+            };
+            if decoded == 0 {
+                return Err(format!("{} sample operation decoded to no data, the payload may be corrupt", kind.as_str_name()).into());
             }
+            let rate = decoded as f64 / start.elapsed().as_secs_f64().max(1e-6);
+
+            seconds += of_kind().map(|op| output_len(op)).sum::<u64>() as f64 / rate;
         }
 
-        Ok("Done".into())
+        // Zero operations are left as holes in the output, so they cost next to nothing.
+        let written: u64 = operations.iter().filter(|op| op.r#type() != Type::Zero).map(|op| output_len(op)).sum();
+        let write_rate = probe_write_rate(out_dir).map_err(|err| format!("failed to probe write speed in {}: {}", out_dir, err))?;
+        seconds += written as f64 / write_rate;
+
+        Duration::try_from_secs_f64(seconds).map_err(|_| format!("cannot estimate a duration from {} seconds", seconds).into())
     }
 
-    fn extract_selected<'p>(&mut self, partition: &PartitionUpdate, out_file: &str, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<(), Box<dyn Error>> {
-        let mut output_file = match File::create(out_file) {
-            Ok(f) => {
-                f
+    /// Checks which of `names` (every partition if empty) this build can extract, without
+    /// reading any operation data, so unsupported partitions can be deselected up front.
+    pub fn preflight(&mut self, names: &[String]) -> Result<Vec<PreflightResult>, Box<dyn Error>> {
+        self.check_known(names)?;
+        let manifest = self.manifest()?;
+        Ok(manifest.partitions.iter()
+            .filter(|p| names.is_empty() || names.contains(&p.partition_name))
+            .map(|partition| {
+                let mut result = PreflightResult { partition: partition.partition_name.clone(), first_unsupported_op: None, unsupported_types: Vec::new() };
+                for (op_index, operation) in partition.operations.iter().enumerate().filter(|(_, op)| !operation_supported(op)) {
+                    result.first_unsupported_op.get_or_insert(op_index);
+                    if !result.unsupported_types.contains(&operation.r#type) {
+                        result.unsupported_types.push(operation.r#type);
+                    }
+                }
+                result
+            })
+            .collect())
+    }
+
+    fn extract_selected<'p>(&mut self, partition: &PartitionUpdate, out_file: &str, options: &ExtractOptions, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<ExtractResult, Box<dyn Error>> {
+        let size = partition.new_partition_info.as_ref().and_then(|info| info.size).ok_or(Box::new(CError("partition size not found".into())))?;
+        check_output_path(out_file, options.symlinks, options.in_place)?;
+        // Opening the output truncates it, which would destroy the payload mid-read.
+        if same_file(Path::new(out_file), Path::new(&self.path)) {
+            return Err(format!("output {} is the payload being extracted, choose another path", out_file).into());
+        }
+        if options.format == OutputFormat::Raw && !options.in_place && is_contiguous(partition) {
+            let mut result = self.write_contiguous(partition, out_file, size, options, onprogress, onverify)?;
+            result.out_file = out_file.into();
+            return Ok(result);
+        }
+        let opened: io::Result<Box<dyn OutputSink>> = match options.format {
+            OutputFormat::Raw if options.in_place => FileSink::open_in_place(out_file, size).map(|sink| Box::new(sink) as Box<dyn OutputSink>),
+            OutputFormat::Raw => FileSink::create(out_file, size).map(|sink| Box::new(sink) as Box<dyn OutputSink>),
+            OutputFormat::Sparse if options.in_place => return Err("sparse output cannot be updated in place".into()),
+            OutputFormat::Sparse => File::create(out_file)
+                .and_then(|file| SparseSink::new(file, size, BLOCK_SIZE as u32))
+                .map(|sink| Box::new(sink) as Box<dyn OutputSink>),
+        };
+        let mut sink = match opened {
+            Ok(sink) => {
+                sink
+            }
+            Err(err) => {
+                return Err(format!("file create error: {}", err).into());
             }
+        };
+        let mut result = self.write_partition(partition, sink.as_mut(), options, onprogress, onverify)?;
+        result.out_file = out_file.into();
+        Ok(result)
+    }
+
+    /// Fast path for [`is_contiguous`] partitions: the image is one range of the data region,
+    /// copied as is without decoding or seeking. Operation and image hashes are checked
+    /// according to `hash_granularity`, as on the general path.
+    fn write_contiguous<'p>(&self, partition: &PartitionUpdate, out_file: &str, size: u64, options: &ExtractOptions, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<ExtractResult, Box<dyn Error>> {
+        let start = self.data_position(partition.operations[0].data_offset.unwrap_or(0))?;
+        if start.checked_add(size).is_none_or(|end| end > self.end) {
+            return Err(format!("data for partition: {} runs past the end of the payload", partition.partition_name).into());
+        }
+        let mut sink = match FileSink::create(out_file, size) {
+            Ok(sink) => sink,
             Err(err) => {
                 return Err(format!("file create error: {}", err).into());
             }
         };
+        let mut file = self.file()?;
+        file.seek(SeekFrom::Start(start))?;
+
+        let total_operations = partition.operations.len();
+        let check_operations = options.hash_granularity != HashGranularity::PartitionOnly;
+        let check_partition = options.hash_granularity != HashGranularity::PerOperation;
+        let mut stream_hash = StreamHash::new(self.hasher_if(check_partition), options.crc32, true);
+        let mut sha_buf = self.hasher_if(check_operations);
+        let mut buf = vec![0; buffer_len(options)];
+        let mut position = 0;
+        for (op_index, operation) in partition.operations.iter().enumerate() {
+            let mut remaining = operation.data_length.unwrap_or(0);
+            while remaining > 0 {
+                let len = remaining.min(buf.len() as u64) as usize;
+                let chunk = &mut buf[..len];
+                file.read_exact(chunk)?;
+                sink.write_data(chunk)?;
+                sha_buf.update(chunk);
+                stream_hash.update(position, chunk);
+                position += chunk.len() as u64;
+                remaining -= chunk.len() as u64;
+            }
+            let new_hash = sha_buf.finalize_reset();
+            let expected_hash = operation.data_sha256_hash();
+            if check_operations && !expected_hash.is_empty() && new_hash.as_slice() != expected_hash {
+                return Err("Operation Hash mismatch error".into());
+            }
+            onprogress(((op_index + 1) * 100) / total_operations);
+        }
+        sink.finalize()?;
+
+        onverify(0);
+        let (new_hash, crc32) = stream_hash.finish(size).ok_or(Box::new(CError("partition was not written in order".into())))?;
+        let new_hash = if check_partition { new_hash } else { String::new() };
+        let hash = hex::encode(partition.new_partition_info.as_ref().and_then(|info| info.hash.as_ref()).ok_or(Box::new(CError("partition hash not found".into())))?);
+        if check_partition && hash != new_hash {
+            onverify(2);
+            return Err("Partition Hash mismatch error".into());
+        }
+        onverify(1);
+
+        Ok(ExtractResult {
+            partition: partition.partition_name.clone(),
+            out_file: String::new(),
+            size,
+            expected_hash: hash,
+            computed_hash: new_hash,
+            crc32,
+            error: None,
+            short_operations: Vec::new(),
+            skipped_operations: Vec::new(),
+            unverifiable: false,
+        })
+    }
+
+    /// Extracts a partition into a caller-provided sink, e.g. a [`crate::sink::StreamingSink`] over a
+    /// pipe. `out_file` is left empty in the returned result.
+    pub fn extract_to_sink<'p>(&mut self, partition_to_extract: &str, sink: &mut dyn OutputSink, options: &ExtractOptions, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<ExtractResult, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let partition = manifest.partitions.iter()
+            .find(|p| p.partition_name == partition_to_extract)
+            .cloned()
+            .ok_or_else(|| format!("partition: {} not found in {}", partition_to_extract, &self.path))?;
+        self.write_partition(&partition, sink, options, onprogress, onverify)
+    }
+
+    fn write_partition<'p>(&mut self, partition: &PartitionUpdate, sink: &mut dyn OutputSink, options: &ExtractOptions, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<ExtractResult, Box<dyn Error>> {
         let name = &partition.partition_name;
         let total_operations = partition.operations.len();
-        let size = partition.new_partition_info.as_ref().ok_or(Box::new(CError("partition size not found".into())))?.size.expect("size not found");
+        let size = partition.new_partition_info.as_ref().and_then(|info| info.size).ok_or(Box::new(CError("partition size not found".into())))?;
+        // Fail before writing anything rather than at the first xz operation.
+        if partition.operations.iter().any(|op| op.r#type == Type::ReplaceXz as i32) {
+            if let Err(reason) = probe_xz() {
+                return Err(PayloadError::XzUnavailable { reason: reason.clone() }.into());
+            }
+        }
+        let in_place = sink.holds_source_image();
         let mut progress_track: usize = 0;
+        let check_operations = options.hash_granularity != HashGranularity::PartitionOnly;
+        let check_partition = options.hash_granularity != HashGranularity::PerOperation;
+        let mut stream_hash = StreamHash::new(self.hasher_if(check_partition), options.crc32, !in_place);
+        let mut short_operations = Vec::new();
+        let mut skipped_operations = Vec::new();
+        // Reused across operations; partitions can have thousands of small ones.
+        let mut buf = Vec::new();
+        let mut sha_buf = self.hasher_if(check_operations);
 
-        let mut reader = BufReader::new(&self.file);
-
-        for operation in &partition.operations {
+        for (op_index, operation) in partition.operations.iter().enumerate() {
             if operation.dst_extents.is_empty() {
                 return Err(format!("invalid dstextents for partition: {}", name).into());
             }
+            if operation.data_length.unwrap_or(0) == 0 && replaces_data(operation) {
+                return Err(PayloadError::EmptyOperationData { op_index, op_type: operation.r#type() }.into());
+            }
 
-            let dst = operation.dst_extents[0];
-            let data_offset = operation.data_offset.unwrap_or(0) + self.header.as_ref().ok_or(Box::new(CError("header not found".into())))?.data_offset;
-            let data_length = operation.data_length.unwrap_or(0);
-            let expected_uncompress_block_size = dst.num_blocks() * BLOCK_SIZE;
-
-            let _ = reader.seek(SeekFrom::Start(self.zip_offset + data_offset));
-            let mut reader = Read::take(&mut reader, data_length);
+            let expected_uncompress_block_size: u64 = operation.dst_extents.iter().map(|e| e.num_blocks() * BLOCK_SIZE).sum();
 
-            let mut sha_buf = Sha256::new();
-            let mut buf = vec![0; data_length as usize];
-            let _ = reader.read(&mut buf)?;
+            let (data_offset, data_length) = (operation.data_offset.unwrap_or(0), operation.data_length.unwrap_or(0));
+            let mut data: Box<dyn Read> = if options.max_memory.is_some_and(|limit| data_length > limit / 4) {
+                Box::new(HashingReader { inner: self.data_reader(data_offset, data_length)?, hasher: sha_buf.as_mut() })
+            } else {
+                self.read_range_into(data_offset, data_length, &mut buf)?;
+                sha_buf.update(&buf);
+                Box::new(buf.as_slice())
+            };
 
-            sha_buf.update(&mut buf);
-            
-            let bytes_written: u64;
-            match operation.r#type() {
+            let mut output = ExtentWriter::new(sink, &operation.dst_extents, &mut stream_hash);
+            let bytes_written: u64 = match operation.r#type() {
                 Type::Replace => {
-                    bytes_written = io::copy(&mut buf.as_slice(), &mut output_file)?;
+                    io::copy(&mut data, &mut output)?
                 },
                 Type::ReplaceXz => {
-                    let mut decoder = XzDecoder::new(buf.as_slice());
-                    bytes_written = io::copy(&mut decoder, &mut output_file)?;
+                    let mut decoder = XzDecoder::new(&mut data);
+                    copy_decoded(&mut decoder, &mut output, op_index, Type::ReplaceXz)?
                 },
                 Type::ReplaceBz => {
-                    let mut decoder = BzDecoder::new(buf.as_slice());
-                    bytes_written = io::copy(&mut decoder, &mut output_file)?;
+                    let mut decoder = BzDecoder::new(&mut data);
+                    copy_decoded(&mut decoder, &mut output, op_index, Type::ReplaceBz)?
                 },
                 Type::Zero => {
-                    let mut filler = io::repeat(0).take(expected_uncompress_block_size);
-                    bytes_written = io::copy(&mut filler, &mut output_file)?;
+                    output.write_zeros()?
+                },
+                Type::SourceCopy if in_place => {
+                    let mut source_hash = self.hasher_if(operation.src_sha256_hash.as_ref().is_some_and(|hash| !hash.is_empty()));
+                    if operation.src_extents == operation.dst_extents {
+                        expected_uncompress_block_size
+                    } else if options.max_memory.is_some() && !extents_overlap(&operation.src_extents, &operation.dst_extents) {
+                        // Chunks are only safe when the copy can't overwrite source blocks
+                        // before reading them, and the source hash is checked afterwards.
+                        let written = copy_extents(sink, &operation.src_extents, &operation.dst_extents, &mut stream_hash, source_hash.as_mut(), buffer_len(options))?;
+                        check_source_hash(operation, op_index, source_hash.as_mut())?;
+                        written
+                    } else {
+                        let source = read_extents(sink, &operation.src_extents)?;
+                        source_hash.update(&source);
+                        check_source_hash(operation, op_index, source_hash.as_mut())?;
+                        let mut output = ExtentWriter::new(sink, &operation.dst_extents, &mut stream_hash);
+                        io::copy(&mut source.as_slice(), &mut output)?
+                    }
+                },
+                _ if options.salvage => {
+                    skipped_operations.push(SkippedOperation {
+                        op_index,
+                        op_type: operation.r#type,
+                        extents: operation.dst_extents.iter().map(BlockRange::from_extent).collect(),
+                    });
+                    output.write_zeros()?
                 },
                 _ => {
                     return Err(format!("Unsupported operation type: {}", operation.r#type).into());
                 }
-            }
-            if bytes_written != expected_uncompress_block_size {
+            };
+            // Streamed data is only fully hashed once it has been read to the end.
+            io::copy(&mut data, &mut io::sink())?;
+            drop(data);
+            if options.diagnostics && bytes_written < expected_uncompress_block_size {
+                short_operations.push(ShortOperation { op_index, op_type: operation.r#type(), expected: expected_uncompress_block_size, written: bytes_written });
+            } else if bytes_written != expected_uncompress_block_size {
                 return Err("Unexpected byte written".into());
             }
-            let new_hash = hex::encode(sha_buf.finalize());
-            let expected_hash = hex::encode(operation.data_sha256_hash());
-            if expected_hash != "" {
-                if new_hash != expected_hash {
-                    return Err("Operation Hash mismatch error".into());
-                }
+            let new_hash = sha_buf.finalize_reset();
+            let expected_hash = operation.data_sha256_hash();
+            if check_operations && !expected_hash.is_empty() && new_hash.as_slice() != expected_hash {
+                return Err("Operation Hash mismatch error".into());
             }
             progress_track += 1;
             onprogress((progress_track * 100) / total_operations);
         }
 
-        output_file.flush()?;
+        sink.finalize()?;
         onverify(0);
-		let mut hasher = Sha256::new();
-		let file = File::open(&out_file)?;
-		let mut reader = BufReader::new(file);
-        let buf_size = if size > 1024 * 1024 { 1024 * 1024 } else { size as usize };
-		let mut buf = vec![0; buf_size];
-		loop {
-            let bytes_read = reader.read(&mut buf)?;
-            if bytes_read == 0 {
-                    break;
-            }
-            hasher.update(&buf[..bytes_read]);
-        }
-		let new_hash = hex::encode(hasher.finalize());
+        // The image is normally hashed while it is written; only out-of-order writes need
+        // it to be read back.
+        let (new_hash, crc32) = match stream_hash.finish(size) {
+            _ if !check_partition && !options.crc32 => (String::new(), None),
+            Some(digest) => digest,
+            None => match sink.as_file().filter(|_| options.mmap_verify).and_then(|file| hash_mapped(file, self.hasher_if(check_partition), size, options.crc32)) {
+                Some(digest) => digest,
+                None => hash_sink(sink, self.hasher_if(check_partition), size, options.crc32, buffer_len(options))?,
+            },
+        };
+        let new_hash = if check_partition { new_hash } else { String::new() };
         let hash_encoded = partition.new_partition_info.as_ref().ok_or(Box::new(CError("partition hash not found".into())))?.hash.as_ref().ok_or(Box::new(CError("partition hash not found".into())))?.clone();
         let hash = hex::encode(hash_encoded);
-        if hash != new_hash {
-            onverify(2);
+        // Without the image hash, skipped or short operations are what gives a bad image away.
+        let mismatch = if check_partition { hash != new_hash } else { !skipped_operations.is_empty() || !short_operations.is_empty() };
+        onverify(if mismatch { 2 } else { 1 });
+        // A salvaged image is expected to mismatch and is still returned, as partial.
+        if mismatch && skipped_operations.is_empty() {
+            if !short_operations.is_empty() {
+                return Err(PayloadError::ShortOperations { partition: name.clone(), operations: short_operations }.into());
+            }
             return Err("Partition Hash mismatch error".into());
         }
-        onverify(1);
 
-        Ok(())
+        Ok(ExtractResult {
+            partition: name.clone(),
+            out_file: String::new(),
+            size,
+            expected_hash: hash,
+            computed_hash: new_hash,
+            crc32,
+            error: None,
+            short_operations,
+            skipped_operations,
+            unverifiable: false,
+        })
+    }
+
+    /// Checks the images in `dir` against a `sha256sum`-style file of `<hex>  <filename>`
+    /// lines, such as a checksum list published alongside a firmware release. Results follow
+    /// the order of `sums_file`, with `partition` holding the file name; files listed there
+    /// but absent from `dir` are [`VerifyStatus::MissingFile`], and `.img` files in `dir`
+    /// that aren't listed come last as [`VerifyStatus::MissingFromList`].
+    pub fn verify_output_dir(&mut self, dir: &str, sums_file: &str) -> Result<Vec<VerifyResult>, Box<dyn Error>> {
+        let content = fs::read_to_string(sums_file).map_err(|err| format!("failed to read {}: {}", sums_file, err))?;
+        let mut results = Vec::new();
+        for (line_index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line.split_once(char::is_whitespace)
+                .map(|(hash, file_name)| (hash, file_name.trim_start().trim_start_matches('*')))
+                .filter(|(hash, file_name)| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) && !file_name.is_empty());
+            let Some((hash, file_name)) = parsed else {
+                return Err(format!("{} line {}: expected `<sha256>  <filename>`", sums_file, line_index + 1).into());
+            };
+            let expected = hash.to_ascii_lowercase();
+            let path = Path::new(dir).join(file_name);
+            let (actual, status) = if path.is_file() {
+                let actual = self.hash_file(&path)?;
+                let status = if actual == expected { VerifyStatus::Match } else { VerifyStatus::Mismatch };
+                (Some(actual), status)
+            } else {
+                (None, VerifyStatus::MissingFile)
+            };
+            results.push(VerifyResult { partition: file_name.into(), expected: Some(expected), actual, status });
+        }
+
+        let mut extra: Vec<String> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".img") && !results.iter().any(|result| result.partition.trim_start_matches("./") == name))
+            .collect();
+        extra.sort();
+        for file_name in extra {
+            let actual = self.hash_file(&Path::new(dir).join(&file_name))?;
+            results.push(VerifyResult { partition: file_name, expected: None, actual: Some(actual), status: VerifyStatus::MissingFromList });
+        }
+        Ok(results)
+    }
+
+    fn hash_file(&self, path: &Path) -> Result<String, Box<dyn Error>> {
+        let mut file = File::open(path).map_err(|err| format!("failed to open {}: {}", path.display(), err))?;
+        let mut hasher = self.hash.sha256();
+        let mut buf = vec![0; 1024 * 1024];
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+        Ok(hex::encode(hasher.finalize_reset()))
+    }
+
+    /// Hex SHA256 of the payload metadata, that is the header and manifest.
+    pub fn metadata_hash(&mut self) -> Result<String, Box<dyn Error>> {
+        self.init()?;
+
+        let metadata_size = self.header.as_ref().ok_or(Box::new(CError("header not found".into())))?.metadata_size;
+        self.file()?.seek(SeekFrom::Start(self.zip_offset))?;
+        let mut metadata = Vec::new();
+        if Read::take(self.file()?, metadata_size).read_to_end(&mut metadata)? as u64 != metadata_size {
+            return Err("failed to read payload metadata".into());
+        }
+        let mut hasher = self.hash.sha256();
+        hasher.update(&metadata);
+        Ok(hex::encode(hasher.finalize_reset()))
+    }
+
+    /// Reads the metadata and payload signatures. Either is simply empty when the payload
+    /// has no signature region for it, so unsigned payloads report
+    /// [`PayloadSignatures::unsigned`] rather than an error.
+    pub fn signatures(&mut self) -> Result<PayloadSignatures, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        let header = self.header.as_ref().ok_or(Box::new(CError("header not found".into())))?;
+        let (signature_len, metadata_size) = (header.signature_len as u64, header.metadata_size);
+        let (signatures_offset, signatures_size) = (manifest.signatures_offset.unwrap_or(0), manifest.signatures_size.unwrap_or(0));
+
+        let mut signatures = PayloadSignatures::default();
+        if signature_len > 0 {
+            let start = self.zip_offset + metadata_size;
+            if start.checked_add(signature_len).is_none_or(|end| end > self.end) {
+                return Err("metadata signature runs past the end of the payload".into());
+            }
+            self.file()?.seek(SeekFrom::Start(start))?;
+            let mut blob = vec![0; signature_len as usize];
+            self.file()?.read_exact(&mut blob)?;
+            signatures.metadata = decode_signatures(&blob)?;
+        }
+        if signatures_size > 0 {
+            signatures.payload = decode_signatures(&self.read_range(signatures_offset, signatures_size)?)?;
+        }
+        Ok(signatures)
+    }
+
+    /// Short identifier for recognising a payload seen before, built from the metadata hash,
+    /// partition count, total image size and security patch level. Only the metadata is
+    /// read, so this is cheap for any size of payload. It is not a cryptographic guarantee
+    /// that two payloads have the same content: the data region itself is never hashed.
+    pub fn payload_fingerprint(&mut self) -> Result<String, Box<dyn Error>> {
+        let metadata_hash = self.metadata_hash()?;
+        let manifest = self.manifest()?;
+        let total_size: u64 = manifest.partitions.iter().map(|p| p.new_partition_info.as_ref().and_then(|info| info.size).unwrap_or(0)).sum();
+        let fields = format!("{}|{}|{}|{}", metadata_hash, manifest.partitions.len(), total_size, manifest.security_patch_level());
+        let mut hasher = self.hash.sha256();
+        hasher.update(fields.as_bytes());
+        Ok(hex::encode(&hasher.finalize_reset()[..16]))
+    }
+
+    /// Compares each partition's manifest hash with `expected`, a map from partition name to
+    /// hex SHA-256 taken from a trusted source. Partitions come back in manifest order,
+    /// followed by expected names the payload doesn't have, sorted by name.
+    pub fn verify_against(&mut self, expected: &HashMap<String, String>) -> Result<Vec<VerifyResult>, Box<dyn Error>> {
+        let partitions = self.partitions()?;
+
+        let mut results: Vec<VerifyResult> = partitions.into_iter().map(|partition| {
+            let expected = expected.get(&partition.name).map(|hash| hash.trim().to_ascii_lowercase());
+            let status = match &expected {
+                None => VerifyStatus::MissingFromList,
+                Some(hash) if *hash == partition.hash => VerifyStatus::Match,
+                Some(_) => VerifyStatus::Mismatch,
+            };
+            VerifyResult { partition: partition.name, expected, actual: Some(partition.hash), status }
+        }).collect();
+
+        let mut missing: Vec<(&String, &String)> = expected.iter()
+            .filter(|(name, _)| !results.iter().any(|result| &result.partition == *name))
+            .collect();
+        missing.sort();
+        results.extend(missing.into_iter().map(|(name, hash)| VerifyResult {
+            partition: name.clone(),
+            expected: Some(hash.trim().to_ascii_lowercase()),
+            actual: None,
+            status: VerifyStatus::MissingFromPayload,
+        }));
+        Ok(results)
+    }
+
+    /// Parses the payload once and turns it into a [`SharedPayload`] for use across threads.
+    pub fn into_shared(mut self) -> Result<SharedPayload, Box<dyn Error>> {
+        self.init()?;
+        Ok(SharedPayload {
+            header: self.header.take().ok_or(Box::new(CError("header not found".into())))?,
+            manifest: self.manifest.take().ok_or(Box::new(CError("manifest not found".into())))?,
+            path: self.path,
+            zip_offset: self.zip_offset,
+            end: self.end,
+            temp_copy: self.temp_copy,
+            hash: self.hash,
+        })
+    }
+
+    /// Groups the partitions by base name and slot, in the order each base name first
+    /// appears in the manifest. Full names are kept in each [`PartitionInfo`].
+    pub fn partitions_by_slot(&mut self) -> Result<Vec<SlotGroup>, Box<dyn Error>> {
+        let mut groups: Vec<SlotGroup> = Vec::new();
+        for partition in self.partitions()? {
+            let (base_name, slot) = split_slot(&partition.name);
+            match groups.iter_mut().find(|group| group.base_name == base_name) {
+                Some(group) => group.slots.push((slot, partition)),
+                None => groups.push(SlotGroup { base_name: base_name.into(), slots: vec![(slot, partition)] }),
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Verity descriptors for each partition that has a hash tree or FEC data.
+    pub fn verity_info(&mut self) -> Result<Vec<VerityInfo>, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        Ok(manifest.partitions.iter()
+            .filter(|partition| partition.hash_tree_extent.is_some() || partition.fec_extent.is_some())
+            .map(|partition| VerityInfo {
+                partition: partition.partition_name.clone(),
+                hash_tree_data: partition.hash_tree_data_extent.as_ref().map(BlockRange::from_extent),
+                hash_tree: partition.hash_tree_extent.as_ref().map(BlockRange::from_extent),
+                hash_tree_algorithm: partition.hash_tree_algorithm.clone(),
+                hash_tree_salt: partition.hash_tree_salt.as_ref().map(hex::encode),
+                fec_data: partition.fec_data_extent.as_ref().map(BlockRange::from_extent),
+                fec: partition.fec_extent.as_ref().map(BlockRange::from_extent),
+                fec_roots: partition.fec_roots,
+                hash_tree_written: partition.hash_tree_extent.as_ref().is_some_and(|extent| written_by_operations(partition, extent)),
+                fec_written: partition.fec_extent.as_ref().is_some_and(|extent| written_by_operations(partition, extent)),
+            })
+            .collect())
+    }
+
+    /// COW size estimates for the partitions that carry one. Payloads built without Virtual
+    /// A/B support have none, which gives an empty list.
+    pub fn cow_info(&mut self) -> Result<Vec<CowInfo>, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        Ok(manifest.partitions.iter()
+            .filter_map(|partition| Some(CowInfo {
+                partition: partition.partition_name.clone(),
+                estimate_cow_size: partition.estimate_cow_size?,
+                estimate_op_count_max: partition.estimate_op_count_max,
+            }))
+            .collect())
+    }
+
+    pub fn partitions(&mut self) -> Result<Vec<PartitionInfo>, Box<dyn Error>> {
+        let manifest = self.manifest()?;
+        Ok(manifest.partitions.iter().map(PartitionInfo::from_update).collect())
+    }
+
+    /// Partitions ordered by image size, largest first if `descending`. Equal sizes are
+    /// ordered by name.
+    pub fn partitions_sorted_by_size(&mut self, descending: bool) -> Result<Vec<PartitionInfo>, Box<dyn Error>> {
+        let mut partitions = self.partitions()?;
+        partitions.sort_by(|a, b| {
+            let by_size = if descending { b.size.cmp(&a.size) } else { a.size.cmp(&b.size) };
+            by_size.then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(partitions)
     }
 
     pub fn get_partition_list(&mut self) -> Result<String, Box<dyn Error>> {
 
         let mut msg: String = Default::default();
 
-        if let Err(err) = self.init() {
-            return Err(err);
-        }
+        let partitions = self.partitions()?;
         
         if let Some(manifest) = &self.manifest {
 
@@ -265,12 +1636,8 @@ impl Payload {
             msg.insert_str(msg.len(), format!("data:{}|{}|{}|{}:", header.version, header.manifest_len, header.signature_len, manifest.security_patch_level()).as_str());
             }
 
-            for (_i, partition) in manifest.partitions.iter().enumerate() {
-                let partition_name = &partition.partition_name;
-                let partition_size = partition.new_partition_info.as_ref().map_or(0, |info| info.size.expect("info size not found"));
-                let partition_hash = partition.new_partition_info.as_ref().and_then( |info| info.hash.clone()).expect("msg");
-
-                let mg = format!("{}|{:?}|{},", partition_name, partition_size, hex::encode(partition_hash));
+            for partition in partitions.iter() {
+                let mg = format!("{}|{:?}|{}|{}|{}|{},", partition.name, partition.size, partition.hash, partition.operation_count, partition.total_data_length, partition.supported);
                 msg.insert_str(msg.len(), mg.as_str());
 
             }
@@ -280,4 +1647,1246 @@ impl Payload {
         }
         Ok(msg)
     }
-}
\ No newline at end of file
+}
+
+impl Drop for TempCopy {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+impl SharedPayload {
+    /// Opens a new file handle for extracting from this payload. The returned `Payload`
+    /// reuses the parsed metadata and can be moved to another thread.
+    pub fn open(&self) -> Result<Payload, Box<dyn Error>> {
+        let file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(err) => {
+                return Err(format!("Err: {}", err).into());
+            }
+        };
+        Ok(Payload {
+            path: self.path.clone(),
+            file: Some(file),
+            zip_offset: self.zip_offset,
+            end: self.end,
+            header: Some(self.header.clone()),
+            manifest: Some(self.manifest.clone()),
+            temp_copy: self.temp_copy.clone(),
+            hash: self.hash.clone(),
+        })
+    }
+
+    /// Extracts one partition through a fresh file handle, see [`Payload::extract_with`].
+    pub fn extract_with<'p>(&self, partition_to_extract: &str, out_file: &str, options: &ExtractOptions, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<String, Box<dyn Error>> {
+        self.open()?.extract_with(partition_to_extract, out_file, options, onprogress, onverify)
+    }
+
+    pub fn partitions(&self) -> Vec<PartitionInfo> {
+        self.manifest.partitions.iter().map(PartitionInfo::from_update).collect()
+    }
+}
+
+/// Extracts one partition from an OTA zip to `out_path`, finding `payload.bin` inside it.
+/// This is all most callers need; the path is read as a zip whatever its extension.
+///
+/// Fails with [`PayloadError::NotAZip`] if the file is not a zip archive and with
+/// [`PayloadError::PayloadNotFound`] if it has no `payload.bin`.
+pub fn extract_from_zip<'p>(zip_path: &str, partition: &str, out_path: &str, onprogress: &'p dyn Fn(usize), onverify: &'p dyn Fn(i8)) -> Result<ExtractResult, Box<dyn Error>> {
+    let mut file = match File::open(zip_path) {
+        Ok(f) => f,
+        Err(err) => {
+            return Err(format!("Err: {}", err).into());
+        }
+    };
+    let location = zip_location(&mut file, zip_path)?;
+    let mut payload = Payload::open(zip_path.into(), file, location)?;
+    let manifest = payload.manifest()?;
+    let update = manifest.partitions.iter()
+        .find(|p| p.partition_name == partition)
+        .ok_or_else(|| format!("partition: {} not found in {}", partition, zip_path))?;
+    payload.extract_selected(update, out_path, &ExtractOptions::default(), onprogress, onverify)
+}
+
+/// Finds the `payload.bin` entry in the zip archive `file`.
+fn zip_location(file: &mut File, path: &str) -> Result<PayloadLocation, Box<dyn Error>> {
+    let file_len = file.metadata()?.len();
+    let mut archive = ZipArchive::new(&mut *file).map_err(|source| PayloadError::NotAZip { path: path.into(), source })?;
+    let entry = archive.by_name("payload.bin").map_err(|_| PayloadError::PayloadNotFound { path: path.into() })?;
+    // Offsets in zip64 archives are 64-bit and resolved by the zip crate; this catches
+    // any entry that still points outside the file rather than reading garbage.
+    if entry.data_start().checked_add(entry.compressed_size()).is_none_or(|end| end > file_len) {
+        return Err(format!("payload.bin at offset {} ({} bytes) lies outside the zip ({} bytes)", entry.data_start(), entry.compressed_size(), file_len).into());
+    }
+    Ok(PayloadLocation { offset: entry.data_start(), size: entry.compressed_size(), compression: entry.compression() })
+}
+
+/// Operation types this build can extract into a new image. `SOURCE_COPY` is also handled
+/// when extracting in place over an existing image, but no other delta operations are.
+/// `REPLACE_XZ` is left out when the xz decoder fails its self-test.
+pub fn supported_operations() -> Vec<OperationKind> {
+    SUPPORTED_OPERATIONS.into_iter().filter(|&kind| kind_supported(kind)).collect()
+}
+
+/// Checks that `path` is safe to create or truncate: a regular file or nothing yet, and a
+/// symlink only if `symlinks` allows following it. With `block_devices`, which is for
+/// in-place writes, an existing block device is accepted too.
+fn check_output_path(path: &str, symlinks: SymlinkPolicy, block_devices: bool) -> Result<(), Box<dyn Error>> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(format!("cannot check output {}: {}", path, err).into()),
+    };
+    let metadata = if metadata.file_type().is_symlink() {
+        if symlinks == SymlinkPolicy::Refuse {
+            return Err(format!("output {} is a symlink, refusing to write through it", path).into());
+        }
+        match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            // A dangling link creates its target, which is fine once following is allowed.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(format!("cannot resolve output {}: {}", path, err).into()),
+        }
+    } else {
+        metadata
+    };
+    let writable = metadata.is_file() || (block_devices && is_block_device(&metadata));
+    if !writable {
+        return Err(format!("output {} exists and is not a regular file", path).into());
+    }
+    Ok(())
+}
+
+fn is_block_device(metadata: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        metadata.file_type().is_block_device()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        false
+    }
+}
+
+/// Whether `a` and `b` are the same existing file, also through symlinks or hard links.
+fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+/// Whether the partition is `REPLACE` operations that each fill the next blocks of the
+/// image from the next bytes of the data region, covering the whole image.
+fn is_contiguous(partition: &PartitionUpdate) -> bool {
+    let size = partition.new_partition_info.as_ref().and_then(|info| info.size).unwrap_or(0);
+    let Some(first) = partition.operations.first() else {
+        return false;
+    };
+    let mut next_block = 0;
+    let mut next_data = first.data_offset.unwrap_or(0);
+    for operation in &partition.operations {
+        let [extent] = operation.dst_extents.as_slice() else {
+            return false;
+        };
+        let len = extent.num_blocks() * BLOCK_SIZE;
+        if operation.r#type != Type::Replace as i32
+            || extent.start_block() != next_block
+            || operation.data_offset.unwrap_or(0) != next_data
+            || operation.data_length.unwrap_or(0) != len
+        {
+            return false;
+        }
+        next_block += extent.num_blocks();
+        next_data += len;
+    }
+    next_block * BLOCK_SIZE == size
+}
+
+/// Whether every block of `extent` is a destination of some operation in `partition`.
+fn written_by_operations(partition: &PartitionUpdate, extent: &Extent) -> bool {
+    let mut covered: Vec<(u64, u64)> = partition.operations.iter()
+        .flat_map(|op| &op.dst_extents)
+        .map(|e| (e.start_block(), e.start_block() + e.num_blocks()))
+        .collect();
+    covered.sort_unstable();
+    let (mut next, end) = (extent.start_block(), extent.start_block() + extent.num_blocks());
+    for (start, stop) in covered {
+        if start > next {
+            break;
+        }
+        next = next.max(stop);
+        if next >= end {
+            return true;
+        }
+    }
+    next >= end
+}
+
+/// Decodes a tiny xz stream once per process, to check that liblzma works here at all.
+fn probe_xz() -> &'static Result<(), String> {
+    static PROBE: OnceLock<Result<(), String>> = OnceLock::new();
+    PROBE.get_or_init(|| {
+        let mut decoded = Vec::new();
+        match XzDecoder::new(XZ_PROBE.as_slice()).read_to_end(&mut decoded) {
+            Ok(_) if decoded == PAYLOAD_HEADER_MAGIC.as_bytes() => Ok(()),
+            Ok(_) => Err("probe stream decoded to the wrong data".into()),
+            Err(err) => Err(err.to_string()),
+        }
+    })
+}
+
+fn decode_signatures(blob: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let signatures: Signatures = prost::Message::decode(blob)?;
+    Ok(signatures.signatures.into_iter().map(|signature| {
+        let mut data = signature.data.unwrap_or_default();
+        if let Some(len) = signature.unpadded_signature_size {
+            data.truncate(len as usize);
+        }
+        data
+    }).collect())
+}
+
+fn operation_stats(partition: &PartitionUpdate) -> Vec<(OperationKind, usize, u64)> {
+    let mut stats: Vec<(OperationKind, usize, u64)> = Vec::new();
+    for operation in &partition.operations {
+        let Ok(kind) = Type::try_from(operation.r#type) else {
+            continue;
+        };
+        let data_length = operation.data_length.unwrap_or(0);
+        match stats.iter_mut().find(|(k, _, _)| *k == kind) {
+            Some((_, count, bytes)) => {
+                *count += 1;
+                *bytes += data_length;
+            }
+            None => stats.push((kind, 1, data_length)),
+        }
+    }
+    stats
+}
+
+/// Whether the operation writes data carried in the payload, which it can't do without any.
+fn replaces_data(operation: &InstallOperation) -> bool {
+    matches!(Type::try_from(operation.r#type), Ok(Type::Replace | Type::ReplaceBz | Type::ReplaceXz))
+}
+
+fn operation_supported(operation: &InstallOperation) -> bool {
+    Type::try_from(operation.r#type).is_ok_and(kind_supported)
+}
+
+fn kind_supported(kind: OperationKind) -> bool {
+    SUPPORTED_OPERATIONS.contains(&kind) && (kind != Type::ReplaceXz || probe_xz().is_ok())
+}
+
+/// Reads partition names from a text file, one per line. Blank lines and lines starting
+/// with `#` are skipped.
+pub fn read_partition_list(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+fn summary_json(results: &[ExtractResult]) -> String {
+    let entries: Vec<String> = results.iter().map(|result| {
+        let file_name = Path::new(&result.out_file).file_name().map_or(result.out_file.clone(), |name| name.to_string_lossy().into());
+        let crc32 = result.crc32.map_or("null".into(), |crc| format!("\"{:08x}\"", crc));
+        let error = result.error.as_deref().map_or("null".into(), json_string);
+        let status = match &result.error {
+            Some(_) if result.unverifiable => "unverifiable",
+            Some(_) => "failed",
+            None if result.verified() => "verified",
+            None if result.partial() => "partial",
+            None => "mismatch",
+        };
+        let skipped: Vec<String> = result.skipped_operations.iter()
+            .flat_map(|op| op.extents.iter())
+            .map(|range| format!("[{}, {}]", range.start_block, range.num_blocks))
+            .collect();
+        format!(
+            "    {{\"partition\": {}, \"file\": {}, \"size\": {}, \"expected_hash\": \"{}\", \"computed_hash\": \"{}\", \"crc32\": {}, \"status\": \"{}\", \"error\": {}, \"skipped_blocks\": [{}]}}",
+            json_string(&result.partition), json_string(&file_name), result.size, result.expected_hash, result.computed_hash, crc32, status, error, skipped.join(", ")
+        )
+    }).collect();
+    format!("{{\n  \"partitions\": [\n{}\n  ]\n}}\n", entries.join(",\n"))
+}
+
+fn write_patch_pack_readme(path: &Path, results: &[ExtractResult]) -> io::Result<()> {
+    let mut readme = String::from("Patch pack extracted from the OTA payload.\n\n");
+    for name in PATCH_PACK_PARTITIONS {
+        let file_name = |result: &ExtractResult| Path::new(&result.out_file).file_name().map_or(result.out_file.clone(), |name| name.to_string_lossy().into());
+        match results.iter().find(|result| result.partition == *name) {
+            Some(result) if result.verified() => readme.push_str(&format!("{}  sha256 {}\n", file_name(result), result.computed_hash)),
+            Some(result) => readme.push_str(&format!(
+                "{}  FAILED: {}\n", file_name(result), result.error.as_deref().unwrap_or("Partition Hash mismatch error")
+            )),
+            None => readme.push_str(&format!("{}  not in this payload\n", name)),
+        }
+    }
+    readme.push_str("\nTo disable verified boot, flash vbmeta with `fastboot --disable-verity --disable-verification flash vbmeta vbmeta.img`.\n");
+    std::fs::write(path, readme)
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn unknown_partitions(manifest: &DeltaArchiveManifest, partitions: &[String]) -> Vec<String> {
+    partitions.iter()
+        .filter(|name| !manifest.partitions.iter().any(|p| &p.partition_name == *name))
+        .cloned()
+        .collect()
+}
+
+/// Same as `io::copy`, except that read errors are reported as decompression failures so
+/// they can be told apart from errors writing the output.
+fn copy_decoded(decoder: &mut dyn Read, output: &mut dyn Write, op_index: usize, op_type: Type) -> Result<u64, Box<dyn Error>> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut written: u64 = 0;
+    loop {
+        let bytes_read = match decoder.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(source) => return Err(PayloadError::DecompressionFailed { op_index, op_type, source }.into()),
+        };
+        output.write_all(&buf[..bytes_read])?;
+        written += bytes_read as u64;
+    }
+}
+
+/// Checks that `dir` is a writable directory with at least `needed` bytes free.
+fn check_scratch_dir(dir: &Path, needed: u64) -> Result<(), Box<dyn Error>> {
+    if !dir.is_dir() {
+        return Err(format!("scratch directory {} does not exist", dir.display()).into());
+    }
+    let probe = dir.join(format!(".payload-scratch-{}", std::process::id()));
+    if let Err(err) = File::create(&probe) {
+        return Err(format!("scratch directory {} is not writable: {}", dir.display(), err).into());
+    }
+    let _ = fs::remove_file(&probe);
+    match available_space(dir) {
+        Some(available) if available < needed => {
+            Err(format!("not enough space in {}: {} bytes needed, {} available", dir.display(), needed, available).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn available_space(dir: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    // Safety: `path` is NUL-terminated and `stat` is only read after statvfs filled it in.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Writes a short probe file to `dir` and returns the write speed in bytes per second.
+fn probe_write_rate(dir: &str) -> io::Result<f64> {
+    let path = Path::new(dir).join(format!(".payload-probe-{}", std::process::id()));
+    let data = vec![0xa5; WRITE_PROBE_SIZE];
+    let start = Instant::now();
+    let written = File::create(&path).and_then(|mut file| {
+        file.write_all(&data)?;
+        file.sync_all()
+    });
+    let elapsed = start.elapsed();
+    let _ = fs::remove_file(&path);
+    written?;
+    Ok(WRITE_PROBE_SIZE as f64 / elapsed.as_secs_f64().max(1e-6))
+}
+
+fn hash_mapped(file: &File, mut hasher: Box<dyn Sha256Hasher>, size: u64, crc32: bool) -> Option<(String, Option<u32>)> {
+    // Safety: the file was just written by this extraction and is not modified while mapped.
+    let map = unsafe { Mmap::map(file) }.ok()?;
+    let data = map.get(..usize::try_from(size).ok()?)?;
+    let crc = crc32.then(|| CRC32.checksum(data));
+    hasher.update(data);
+    Some((hex::encode(hasher.finalize_reset()), crc))
+}
+
+fn hash_sink(sink: &mut dyn OutputSink, mut hasher: Box<dyn Sha256Hasher>, size: u64, crc32: bool, buf_len: usize) -> Result<(String, Option<u32>), Box<dyn Error>> {
+    let mut crc = crc32.then(|| CRC32.digest());
+    let mut buf = vec![0; size.min(buf_len as u64) as usize];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        if let Err(source) = sink.read_at(offset, &mut buf[..len]) {
+            return Err(PayloadError::Unverifiable { source }.into());
+        }
+        hasher.update(&buf[..len]);
+        if let Some(crc) = crc.as_mut() {
+            crc.update(&buf[..len]);
+        }
+        offset += len as u64;
+    }
+    Ok((hex::encode(hasher.finalize_reset()), crc.map(|crc| crc.finalize())))
+}
+
+/// Hashes an image while it is being written. This only works as long as the writes
+/// arrive in ascending order; otherwise `finish` gives up and the file has to be re-read.
+struct StreamHash {
+    sha: Box<dyn Sha256Hasher>,
+    crc: Option<crc::Digest<'static, u32>>,
+    position: u64,
+    /// Whether ranges that are never written read back as zeros, as in a new file.
+    zero_gaps: bool,
+    in_order: bool,
+}
+
+impl StreamHash {
+    fn new(sha: Box<dyn Sha256Hasher>, crc32: bool, zero_gaps: bool) -> StreamHash {
+        StreamHash { sha, crc: crc32.then(|| CRC32.digest()), position: 0, zero_gaps, in_order: true }
+    }
+
+    fn update(&mut self, offset: u64, data: &[u8]) {
+        if self.advance_to(offset) {
+            self.feed(data);
+            self.position += data.len() as u64;
+        }
+    }
+
+    fn update_zeros(&mut self, offset: u64, len: u64) {
+        if self.advance_to(offset) {
+            self.feed_zeros(len);
+        }
+    }
+
+    fn advance_to(&mut self, offset: u64) -> bool {
+        if self.in_order && self.zero_gaps && offset > self.position {
+            self.feed_zeros(offset - self.position);
+        }
+        self.in_order &= offset == self.position;
+        self.in_order
+    }
+
+    fn feed_zeros(&mut self, len: u64) {
+        let zeros = [0; 4096];
+        let end = self.position + len;
+        while self.position < end {
+            let chunk = (end - self.position).min(zeros.len() as u64);
+            self.feed(&zeros[..chunk as usize]);
+            self.position += chunk;
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        self.sha.update(data);
+        if let Some(crc) = self.crc.as_mut() {
+            crc.update(data);
+        }
+    }
+
+    fn finish(mut self, size: u64) -> Option<(String, Option<u32>)> {
+        if !self.advance_to(size) {
+            return None;
+        }
+        Some((hex::encode(self.sha.finalize_reset()), self.crc.map(|crc| crc.finalize())))
+    }
+}
+
+/// Stands in for SHA-256 where a hash isn't going to be checked.
+struct NoHash;
+
+impl Sha256Hasher for NoHash {
+    fn update(&mut self, _data: &[u8]) {}
+
+    fn finalize_reset(&mut self) -> [u8; 32] {
+        [0; 32]
+    }
+}
+
+/// Hashes everything read through it.
+struct HashingReader<'a, R: Read> {
+    inner: R,
+    hasher: &'a mut dyn Sha256Hasher,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Size of the read buffers used while extracting, kept well under `max_memory`.
+fn buffer_len(options: &ExtractOptions) -> usize {
+    options.max_memory.map_or(1024 * 1024, |limit| (limit / 8).clamp(BLOCK_SIZE, 1024 * 1024) as usize)
+}
+
+fn check_source_hash(operation: &InstallOperation, op_index: usize, source_hash: &mut dyn Sha256Hasher) -> Result<(), Box<dyn Error>> {
+    if let Some(expected) = operation.src_sha256_hash.as_ref().filter(|hash| !hash.is_empty()) {
+        if source_hash.finalize_reset().as_slice() != expected.as_slice() {
+            return Err(format!("Source hash mismatch in operation {}", op_index).into());
+        }
+    }
+    Ok(())
+}
+
+fn extents_overlap(a: &[Extent], b: &[Extent]) -> bool {
+    a.iter().any(|x| b.iter().any(|y| x.start_block() < y.start_block() + y.num_blocks() && y.start_block() < x.start_block() + x.num_blocks()))
+}
+
+/// Copies the `src` blocks of the image to `dst`, `buf_len` bytes at a time, feeding the
+/// source to `source_hash`. The extents must not overlap.
+fn copy_extents(sink: &mut dyn OutputSink, src: &[Extent], dst: &[Extent], hash: &mut StreamHash, source_hash: &mut dyn Sha256Hasher, buf_len: usize) -> io::Result<u64> {
+    let mut buf = vec![0; buf_len];
+    let mut targets = dst.iter().map(|extent| (extent.start_block() * BLOCK_SIZE, extent.num_blocks() * BLOCK_SIZE));
+    let (mut position, mut remaining) = (0, 0);
+    let mut total = 0;
+    for extent in src {
+        let mut offset = extent.start_block() * BLOCK_SIZE;
+        let end = offset + extent.num_blocks() * BLOCK_SIZE;
+        while offset < end {
+            if remaining == 0 {
+                (position, remaining) = targets.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::WriteZero, "operation data exceeds its destination extents")
+                })?;
+                continue;
+            }
+            let chunk = &mut buf[..(end - offset).min(remaining).min(buf_len as u64) as usize];
+            sink.read_at(offset, chunk)?;
+            source_hash.update(chunk);
+            sink.seek_to(position)?;
+            sink.write_data(chunk)?;
+            hash.update(position, chunk);
+            let len = chunk.len() as u64;
+            offset += len;
+            position += len;
+            remaining -= len;
+            total += len;
+        }
+    }
+    Ok(total)
+}
+
+fn read_extents(sink: &mut dyn OutputSink, extents: &[Extent]) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for extent in extents {
+        let start = data.len();
+        data.resize(start + (extent.num_blocks() * BLOCK_SIZE) as usize, 0);
+        sink.read_at(extent.start_block() * BLOCK_SIZE, &mut data[start..])?;
+    }
+    Ok(data)
+}
+
+/// Spreads an operation's output over its destination extents, seeking to the start of
+/// each extent as the previous one fills up.
+struct ExtentWriter<'a> {
+    sink: &'a mut dyn OutputSink,
+    extents: &'a [Extent],
+    hash: &'a mut StreamHash,
+    next: usize,
+    position: u64,
+    remaining: u64,
+}
+
+impl<'a> ExtentWriter<'a> {
+    fn new(sink: &'a mut dyn OutputSink, extents: &'a [Extent], hash: &'a mut StreamHash) -> ExtentWriter<'a> {
+        ExtentWriter { sink, extents, hash, next: 0, position: 0, remaining: 0 }
+    }
+
+    /// Zero-fills every destination extent, returning the number of bytes covered.
+    fn write_zeros(&mut self) -> io::Result<u64> {
+        let mut total = 0;
+        for extent in self.extents {
+            let offset = extent.start_block() * BLOCK_SIZE;
+            let len = extent.num_blocks() * BLOCK_SIZE;
+            self.sink.seek_to(offset)?;
+            self.sink.write_zeros(len)?;
+            self.hash.update_zeros(offset, len);
+            total += len;
+        }
+        Ok(total)
+    }
+}
+
+impl Write for ExtentWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let extent = self.extents.get(self.next).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::WriteZero, "operation data exceeds its destination extents")
+            })?;
+            self.next += 1;
+            self.remaining = extent.num_blocks() * BLOCK_SIZE;
+            self.position = extent.start_block() * BLOCK_SIZE;
+            self.sink.seek_to(self.position)?;
+        }
+        let len = buf.len().min(self.remaining as usize);
+        self.sink.write_data(&buf[..len])?;
+        self.hash.update(self.position, &buf[..len]);
+        self.position += len as u64;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use bzip2::{write::BzEncoder, Compression};
+    use liblzma::write::XzEncoder;
+    use sha2::{Digest, Sha256};
+    use crate::chromeos_update_engine::PartitionInfo as ImageInfo;
+
+    /// How an injected read fault shows up.
+    #[derive(Clone, Copy)]
+    enum Fault {
+        /// The data ends early, as in a truncated download.
+        Eof,
+        /// The read fails, as on an I/O error from storage.
+        Error,
+    }
+
+    thread_local! {
+        /// Bytes the data readers on this thread may still deliver before the fault hits.
+        static READ_FAULT: std::cell::Cell<Option<(u64, Fault)>> = const { std::cell::Cell::new(None) };
+    }
+
+    /// Makes data reads on this thread hit `fault` once `after` more bytes have been read.
+    fn inject_read_fault(after: u64, fault: Fault) {
+        READ_FAULT.set(Some((after, fault)));
+    }
+
+    /// Wraps every [`Payload::data_reader`] in tests to apply [`inject_read_fault`].
+    pub(super) struct FaultyReader<R>(pub(super) R);
+
+    impl<R: Read> Read for FaultyReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((budget, fault)) = READ_FAULT.get() else {
+                return self.0.read(buf);
+            };
+            if budget == 0 {
+                return match fault {
+                    Fault::Eof => Ok(0),
+                    Fault::Error => Err(io::Error::other("injected read fault")),
+                };
+            }
+            let len = buf.len().min(budget.try_into().unwrap_or(usize::MAX));
+            let read = self.0.read(&mut buf[..len])?;
+            READ_FAULT.set(Some((budget - read as u64, fault)));
+            Ok(read)
+        }
+    }
+
+    /// Builds a payload in memory: header, manifest and a data region of the blobs that
+    /// operations point at. There is no metadata signature.
+    #[derive(Default)]
+    struct PayloadBuilder {
+        data: Vec<u8>,
+        partitions: Vec<PartitionUpdate>,
+    }
+
+    impl PayloadBuilder {
+        /// Appends `bytes` to the data region and returns their relative offset.
+        fn blob(&mut self, bytes: &[u8]) -> u64 {
+            let offset = self.data.len() as u64;
+            self.data.extend_from_slice(bytes);
+            offset
+        }
+
+        fn partition(&mut self, name: &str, image: &[u8], operations: Vec<InstallOperation>) {
+            self.partitions.push(PartitionUpdate {
+                partition_name: name.into(),
+                new_partition_info: Some(ImageInfo { size: Some(image.len() as u64), hash: Some(sha256(image)) }),
+                operations,
+                ..Default::default()
+            });
+        }
+
+        fn build(&self) -> Vec<u8> {
+            let manifest = DeltaArchiveManifest {
+                block_size: Some(BLOCK_SIZE as u32),
+                minor_version: Some(0),
+                partitions: self.partitions.clone(),
+                ..Default::default()
+            };
+            let manifest = prost::Message::encode_to_vec(&manifest);
+            let mut payload = PAYLOAD_HEADER_MAGIC.as_bytes().to_vec();
+            payload.extend_from_slice(&BRILLO_MAJOR_PAYLOAD_VERSION.to_be_bytes());
+            payload.extend_from_slice(&(manifest.len() as u64).to_be_bytes());
+            payload.extend_from_slice(&0u32.to_be_bytes());
+            payload.extend_from_slice(&manifest);
+            payload.extend_from_slice(&self.data);
+            payload
+        }
+    }
+
+    /// A temporary directory removed again when dropped.
+    struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        fn new(name: &str) -> ScratchDir {
+            let path = std::env::temp_dir().join(format!("payload-test-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            ScratchDir(path)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        Sha256::digest(data).to_vec()
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed) ^ (i >> 12) as u8).collect()
+    }
+
+    fn extent(start_block: u64, num_blocks: u64) -> Extent {
+        Extent { start_block: Some(start_block), num_blocks: Some(num_blocks) }
+    }
+
+    /// An operation of `kind` writing `blocks` from the blob `blob` at `data_offset`.
+    fn operation(kind: Type, data_offset: u64, blob: &[u8], blocks: Extent) -> InstallOperation {
+        InstallOperation {
+            r#type: kind as i32,
+            data_offset: Some(data_offset),
+            data_length: Some(blob.len() as u64),
+            data_sha256_hash: Some(sha256(blob)),
+            dst_extents: vec![blocks],
+            ..Default::default()
+        }
+    }
+
+    fn zero(blocks: Extent) -> InstallOperation {
+        InstallOperation { r#type: Type::Zero as i32, dst_extents: vec![blocks], ..Default::default() }
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bz(data: &[u8]) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// A `boot` partition of eight blocks built from plain, xz, bzip2 and zero operations,
+    /// returned with its image.
+    fn sample_payload() -> (Vec<u8>, Vec<u8>) {
+        let block = BLOCK_SIZE as usize;
+        let mut image = pattern(8 * block, 7);
+        image[6 * block..].fill(0);
+        let mut builder = PayloadBuilder::default();
+        let plain = &image[..2 * block];
+        let packed = xz(&image[2 * block..4 * block]);
+        let bzipped = bz(&image[4 * block..6 * block]);
+        let operations = vec![
+            operation(Type::Replace, builder.blob(plain), plain, extent(0, 2)),
+            operation(Type::ReplaceXz, builder.blob(&packed), &packed, extent(2, 2)),
+            operation(Type::ReplaceBz, builder.blob(&bzipped), &bzipped, extent(4, 2)),
+            zero(extent(6, 2)),
+        ];
+        builder.partition("boot", &image, operations);
+        (builder.build(), image)
+    }
+
+    /// Writes `payload` as a stored `payload.bin` into a new zip at `path`, after another
+    /// entry so that it doesn't start at offset zero.
+    fn write_zip(path: &str, payload: &[u8]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("payload_properties.txt", stored).unwrap();
+        zip.write_all(b"FILE_HASH=\nFILE_SIZE=\n").unwrap();
+        zip.start_file("payload.bin", stored).unwrap();
+        zip.write_all(payload).unwrap();
+        zip.finish().unwrap();
+    }
+
+    /// Extracts `partition` into `out_file` and returns the image with the reported
+    /// progress and verify states.
+    fn extract_recorded(payload: &mut Payload, partition: &str, out_file: &str, options: &ExtractOptions) -> (Vec<u8>, Vec<usize>, Vec<i8>) {
+        let progress = RefCell::new(Vec::new());
+        let verify = RefCell::new(Vec::new());
+        payload.extract_with(partition, out_file, options, &|value| progress.borrow_mut().push(value), &|status| verify.borrow_mut().push(status)).unwrap();
+        (fs::read(out_file).unwrap(), progress.into_inner(), verify.into_inner())
+    }
+
+    #[test]
+    fn zip_and_raw_payloads_extract_the_same() {
+        let dir = ScratchDir::new("zip_and_raw");
+        let (payload, image) = sample_payload();
+        let raw_path = dir.path("payload.bin");
+        let zip_path = dir.path("ota.zip");
+        fs::write(&raw_path, &payload).unwrap();
+        write_zip(&zip_path, &payload);
+
+        let mut raw = Payload::new(raw_path).unwrap();
+        let mut zipped = Payload::new(zip_path).unwrap();
+        assert_eq!(raw.location().size, payload.len() as u64);
+        assert_eq!(zipped.location().size, payload.len() as u64);
+        assert!(zipped.location().offset > 0);
+
+        let from_raw = extract_recorded(&mut raw, "boot", &dir.path("raw.img"), &ExtractOptions::default());
+        let from_zip = extract_recorded(&mut zipped, "boot", &dir.path("zip.img"), &ExtractOptions::default());
+        assert_eq!(from_raw.0, image);
+        assert_eq!(from_raw, from_zip);
+        assert_eq!(from_raw.1.last(), Some(&100));
+        assert_eq!(from_raw.2, vec![0, 1]);
+    }
+
+    #[test]
+    fn estimate_duration_rejects_empty_samples() {
+        let dir = ScratchDir::new("estimate_empty");
+        let packed = bz(&[]);
+        let mut builder = PayloadBuilder::default();
+        let operations = vec![operation(Type::ReplaceBz, builder.blob(&packed), &packed, extent(0, 1))];
+        builder.partition("boot", &[0; BLOCK_SIZE as usize], operations);
+        let payload_path = dir.path("payload.bin");
+        fs::write(&payload_path, builder.build()).unwrap();
+
+        let mut payload = Payload::new(payload_path).unwrap();
+        let err = payload.estimate_duration(&[], &dir.0.to_string_lossy()).unwrap_err();
+        assert!(err.to_string().contains("decoded to no data"));
+    }
+
+    #[test]
+    fn missing_partition_size_is_an_error() {
+        let dir = ScratchDir::new("missing_size");
+        let image = pattern(BLOCK_SIZE as usize, 8);
+        let mut builder = PayloadBuilder::default();
+        let operations = vec![operation(Type::Replace, builder.blob(&image), &image, extent(0, 1))];
+        builder.partition("boot", &image, operations);
+        builder.partitions[0].new_partition_info.as_mut().unwrap().size = None;
+        let payload_path = dir.path("payload.bin");
+        fs::write(&payload_path, builder.build()).unwrap();
+
+        let mut payload = Payload::new(payload_path).unwrap();
+        let results = payload.verify_all(&|_, _| {}, &|_, _| {}, &|_| {}).unwrap();
+        assert_eq!(results[0].error.as_deref(), Some("partition size not found"));
+        assert!(payload.extract("boot", &dir.path("boot.img"), &|_| {}, &|_| {}).is_err());
+    }
+
+    /// Chunk types of the sparse image `data`, in order.
+    fn sparse_chunk_types(data: &[u8]) -> Vec<u16> {
+        let le32 = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let mut types = Vec::new();
+        let mut at = 28;
+        for _ in 0..le32(20) {
+            types.push(u16::from_le_bytes([data[at], data[at + 1]]));
+            at += le32(at + 8) as usize;
+        }
+        types
+    }
+
+    #[test]
+    fn sparse_output_fills_zero_operations() {
+        let dir = ScratchDir::new("sparse_zero");
+        let (payload, image) = sample_payload();
+        let payload_path = dir.path("payload.bin");
+        fs::write(&payload_path, &payload).unwrap();
+        let mut payload = Payload::new(payload_path).unwrap();
+
+        let sparse_file = dir.path("boot.sparse.img");
+        let options = ExtractOptions { format: OutputFormat::Sparse, ..Default::default() };
+        let (sparse, _, verify) = extract_recorded(&mut payload, "boot", &sparse_file, &options);
+        assert_eq!(verify, vec![0, 1]);
+        // The data operations share one RAW chunk, the ZERO operation is a FILL, and there
+        // is nothing left for a DONT_CARE.
+        assert_eq!(sparse_chunk_types(&sparse), vec![0xcac1, 0xcac2]);
+
+        let raw_file = dir.path("boot.img");
+        crate::sparse::desparse(&sparse_file, &raw_file).unwrap();
+        assert_eq!(fs::read(&raw_file).unwrap(), image);
+    }
+
+    /// Extracts `boot` from the sample payload, after `change` has had a go at it or set up a
+    /// read fault, and returns the error extraction fails with.
+    fn sample_error(options: &ExtractOptions, change: impl FnOnce(&mut Vec<u8>)) -> Box<dyn Error> {
+        let dir = ScratchDir::new("sample_error");
+        let (mut payload, _) = sample_payload();
+        change(&mut payload);
+        let payload_path = dir.path("payload.bin");
+        fs::write(&payload_path, &payload).unwrap();
+        let result = Payload::new(payload_path).and_then(|mut payload| payload.extract_with("boot", &dir.path("boot.img"), options, &|_| {}, &|_| {}));
+        READ_FAULT.set(None);
+        result.unwrap_err()
+    }
+
+    #[test]
+    fn truncated_manifest_is_reported() {
+        let err = sample_error(&ExtractOptions::default(), |payload| payload.truncate(40));
+        assert!(matches!(err.downcast_ref(), Some(PayloadError::TruncatedManifest { available: 16, .. })));
+    }
+
+    #[test]
+    fn read_errors_while_decoding_are_reported() {
+        // Streamed operation data, so the xz decoder is the one that sees the failure.
+        let options = ExtractOptions { max_memory: Some(4), ..Default::default() };
+        let err = sample_error(&options, |_| inject_read_fault(2 * BLOCK_SIZE + 16, Fault::Error));
+        assert!(matches!(err.downcast_ref(), Some(PayloadError::DecompressionFailed { op_index: 1, op_type: Type::ReplaceXz, .. })));
+    }
+
+    #[test]
+    fn early_end_of_data_is_reported() {
+        let err = sample_error(&ExtractOptions::default(), |_| inject_read_fault(BLOCK_SIZE, Fault::Eof));
+        assert_eq!(err.downcast_ref::<io::Error>().map(io::Error::kind), Some(io::ErrorKind::UnexpectedEof));
+
+        // Plain data, stored out of order so that it isn't taken with the contiguous copy.
+        let image = pattern(2 * BLOCK_SIZE as usize, 2);
+        let (first, second) = image.split_at(BLOCK_SIZE as usize);
+        let mut builder = PayloadBuilder::default();
+        let second_offset = builder.blob(second);
+        let operations = vec![
+            operation(Type::Replace, builder.blob(first), first, extent(0, 1)),
+            operation(Type::Replace, second_offset, second, extent(1, 1)),
+        ];
+        builder.partition("boot", &image, operations);
+        let payload = builder.build();
+        let options = ExtractOptions { max_memory: Some(4), diagnostics: true, hash_granularity: HashGranularity::PartitionOnly, ..Default::default() };
+        let err = sample_error(&options, |sample| {
+            *sample = payload;
+            inject_read_fault(BLOCK_SIZE / 2, Fault::Eof);
+        });
+        let Some(PayloadError::ShortOperations { operations, .. }) = err.downcast_ref() else {
+            panic!("expected short operations, got {}", err);
+        };
+        let written: Vec<(usize, u64)> = operations.iter().map(|op| (op.op_index, op.written)).collect();
+        assert_eq!(written, vec![(0, BLOCK_SIZE / 2), (1, 0)]);
+    }
+
+    #[test]
+    fn operations_without_data_are_reported() {
+        let mut builder = PayloadBuilder::default();
+        let image = [0; BLOCK_SIZE as usize];
+        let mut operations = vec![operation(Type::Replace, builder.blob(&image), &image, extent(0, 1))];
+        operations[0].data_length = Some(0);
+        builder.partition("boot", &image, operations);
+        let payload = builder.build();
+        let err = sample_error(&ExtractOptions::default(), |sample| *sample = payload);
+        assert!(matches!(err.downcast_ref(), Some(PayloadError::EmptyOperationData { op_index: 0, op_type: Type::Replace })));
+    }
+
+    #[test]
+    fn salvage_returns_partial_images() {
+        let dir = ScratchDir::new("salvage");
+        let image = pattern(2 * BLOCK_SIZE as usize, 5);
+        let (first, second) = image.split_at(BLOCK_SIZE as usize);
+        let mut builder = PayloadBuilder::default();
+        let operations = vec![
+            operation(Type::Replace, builder.blob(first), first, extent(0, 1)),
+            operation(Type::SourceBsdiff, builder.blob(second), second, extent(1, 1)),
+        ];
+        builder.partition("boot", &image, operations);
+        let payload_path = dir.path("payload.bin");
+        fs::write(&payload_path, builder.build()).unwrap();
+        let mut payload = Payload::new(payload_path).unwrap();
+        let out_file = dir.path("boot.img");
+        assert!(payload.extract("boot", &out_file, &|_| {}, &|_| {}).is_err());
+
+        let verify = RefCell::new(Vec::new());
+        let options = ExtractOptions { salvage: true, ..Default::default() };
+        let message = payload.extract_with("boot", &out_file, &options, &|_| {}, &|status| verify.borrow_mut().push(status)).unwrap();
+        assert!(message.starts_with("Partial: 1 "));
+        assert_eq!(verify.into_inner(), vec![0, 2]);
+        let mut expected = first.to_vec();
+        expected.extend_from_slice(&[0; BLOCK_SIZE as usize]);
+        assert_eq!(fs::read(&out_file).unwrap(), expected);
+    }
+
+    #[test]
+    fn stale_location_is_refused() {
+        let dir = ScratchDir::new("stale_location");
+        let (payload, _) = sample_payload();
+        let path = dir.path("payload.bin");
+        fs::write(&path, &payload).unwrap();
+        let location = Payload::new(path.clone()).unwrap().location();
+        assert!(Payload::with_location(path.clone(), location).is_ok());
+
+        fs::write(&path, &payload[..payload.len() - 1]).unwrap();
+        let err = Payload::with_location(path, location).err().unwrap();
+        assert!(err.to_string().contains("changed since it was opened"));
+    }
+
+    #[test]
+    fn in_place_source_copy_within_max_memory() {
+        let dir = ScratchDir::new("source_copy");
+        let block = BLOCK_SIZE as usize;
+        let source = pattern(11 * block, 6);
+        let mut image = source.clone();
+        image.copy_within(..4 * block, 4 * block);
+        image.copy_within(8 * block..10 * block, 9 * block);
+        let copy = |src: Extent, dst: Extent, hash: &[u8]| InstallOperation {
+            r#type: Type::SourceCopy as i32,
+            src_extents: vec![src],
+            dst_extents: vec![dst],
+            src_sha256_hash: Some(sha256(hash)),
+            ..Default::default()
+        };
+        let mut builder = PayloadBuilder::default();
+        let operations = vec![
+            copy(extent(0, 4), extent(4, 4), &source[..4 * block]),
+            copy(extent(8, 2), extent(9, 2), &source[8 * block..10 * block]),
+        ];
+        builder.partition("system", &image, operations);
+        let payload_path = dir.path("payload.bin");
+        fs::write(&payload_path, builder.build()).unwrap();
+        let mut payload = Payload::new(payload_path).unwrap();
+
+        let out_file = dir.path("system.img");
+        for max_memory in [None, Some(8 * BLOCK_SIZE)] {
+            fs::write(&out_file, &source).unwrap();
+            let options = ExtractOptions { in_place: true, max_memory, ..Default::default() };
+            let (written, _, verify) = extract_recorded(&mut payload, "system", &out_file, &options);
+            assert_eq!(written, image);
+            assert_eq!(verify, vec![0, 1]);
+        }
+    }
+
+    /// Two partitions whose blobs are interleaved in the shared data region, so their
+    /// operation offsets fall within each other's range.
+    #[test]
+    fn partitions_share_the_data_region() {
+        let dir = ScratchDir::new("shared_region");
+        let block = BLOCK_SIZE as usize;
+        let system = pattern(3 * block, 1);
+        let vendor = pattern(3 * block, 2);
+        let mut builder = PayloadBuilder::default();
+        let mut system_ops = Vec::new();
+        let mut vendor_ops = Vec::new();
+        for index in 0..3 {
+            let range = index * block..(index + 1) * block;
+            system_ops.push(operation(Type::Replace, builder.blob(&system[range.clone()]), &system[range.clone()], extent(index as u64, 1)));
+            vendor_ops.push(operation(Type::Replace, builder.blob(&vendor[range.clone()]), &vendor[range], extent(index as u64, 1)));
+        }
+        builder.partition("system", &system, system_ops);
+        builder.partition("vendor", &vendor, vendor_ops);
+        let payload = builder.build();
+        let raw_path = dir.path("payload.bin");
+        let zip_path = dir.path("ota.zip");
+        fs::write(&raw_path, &payload).unwrap();
+        write_zip(&zip_path, &payload);
+
+        for path in [raw_path, zip_path] {
+            let mut payload = Payload::new(path).unwrap();
+            payload.init().unwrap();
+            let base = payload.location().offset + payload.header.as_ref().unwrap().data_offset;
+            assert_eq!(payload.data_position(block as u64).unwrap(), base + block as u64);
+            assert!(payload.data_position(u64::MAX).is_err());
+
+            let (image, _, _) = extract_recorded(&mut payload, "system", &dir.path("system.img"), &ExtractOptions::default());
+            assert_eq!(image, system);
+            let (image, _, _) = extract_recorded(&mut payload, "vendor", &dir.path("vendor.img"), &ExtractOptions::default());
+            assert_eq!(image, vendor);
+        }
+    }
+
+    /// A partition with blocks that no operation writes, two zeroed by a ZERO operation,
+    /// with its operations in order or reversed.
+    fn holey_payload(reversed: bool) -> (Vec<u8>, Vec<u8>) {
+        let block = BLOCK_SIZE as usize;
+        let mut image = pattern(10 * block, 3);
+        image[2 * block..6 * block].fill(0);
+        image[8 * block..].fill(0);
+        let mut builder = PayloadBuilder::default();
+        let first = &image[..2 * block];
+        let second = &image[6 * block..8 * block];
+        let mut operations = vec![
+            operation(Type::Replace, builder.blob(first), first, extent(0, 2)),
+            zero(extent(4, 2)),
+            operation(Type::Replace, builder.blob(second), second, extent(6, 2)),
+        ];
+        if reversed {
+            operations.reverse();
+        }
+        builder.partition("odm", &image, operations);
+        (builder.build(), image)
+    }
+
+    #[test]
+    fn streamed_hash_matches_read_back() {
+        let dir = ScratchDir::new("streamed_hash");
+        for (reversed, mmap_verify) in [(false, false), (true, false), (true, true)] {
+            let (payload, image) = holey_payload(reversed);
+            let payload_path = dir.path("payload.bin");
+            fs::write(&payload_path, &payload).unwrap();
+            let mut payload = Payload::new(payload_path).unwrap();
+            let manifest = payload.manifest().unwrap();
+            let out_file = dir.path("odm.img");
+            let options = ExtractOptions { crc32: true, mmap_verify, ..Default::default() };
+            let mut sink = FileSink::create(&out_file, image.len() as u64).unwrap();
+            let result = payload.write_partition(&manifest.partitions[0], &mut sink, &options, &|_| {}, &|_| {}).unwrap();
+            drop(sink);
+
+            let written = fs::read(&out_file).unwrap();
+            assert_eq!(written, image);
+            assert_eq!(result.computed_hash, hex::encode(sha256(&written)));
+            assert_eq!(result.crc32, Some(CRC32.checksum(&written)));
+        }
+    }
+
+    #[test]
+    fn verify_all_reports_out_of_order_partitions_as_unverifiable() {
+        let dir = ScratchDir::new("verify_all");
+        let payload_path = dir.path("payload.bin");
+        for reversed in [false, true] {
+            fs::write(&payload_path, holey_payload(reversed).0).unwrap();
+            let mut payload = Payload::new(payload_path.clone()).unwrap();
+            let results = payload.verify_all(&|_, _| {}, &|_, _| {}, &|_| {}).unwrap();
+            assert_eq!(results[0].verified(), !reversed);
+            assert_eq!(results[0].unverifiable, reversed);
+        }
+
+        let (mut payload, _) = sample_payload();
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        fs::write(&payload_path, payload).unwrap();
+        let results = Payload::new(payload_path).unwrap().verify_all(&|_, _| {}, &|_, _| {}, &|_| {}).unwrap();
+        assert!(!results[0].verified());
+        assert!(!results[0].unverifiable);
+    }
+
+    #[test]
+    fn stream_hash_fills_gaps_and_gives_up_out_of_order() {
+        let image = pattern(3 * BLOCK_SIZE as usize + 100, 5);
+        let mut expected = image.clone();
+        expected[100..5000].fill(0);
+        let mut hash = StreamHash::new(Box::new(Sha256::new()), true, true);
+        hash.update(0, &image[..100]);
+        hash.update_zeros(4000, 1000);
+        hash.update(5000, &image[5000..]);
+        let (digest, crc) = hash.finish(image.len() as u64 + 10).unwrap();
+        expected.extend_from_slice(&[0; 10]);
+        assert_eq!(digest, hex::encode(sha256(&expected)));
+        assert_eq!(crc, Some(CRC32.checksum(&expected)));
+
+        let mut hash = StreamHash::new(Box::new(Sha256::new()), false, true);
+        hash.update(BLOCK_SIZE, &image[..100]);
+        hash.update(0, &image[..100]);
+        assert!(hash.finish(image.len() as u64).is_none());
+
+        let mut hash = StreamHash::new(Box::new(Sha256::new()), false, false);
+        hash.update(BLOCK_SIZE, &image[..100]);
+        assert!(hash.finish(image.len() as u64).is_none());
+    }
+
+    #[test]
+    fn contiguous_copy_matches_general_path() {
+        let dir = ScratchDir::new("contiguous");
+        let block = BLOCK_SIZE as usize;
+        let image = pattern(6 * block, 9);
+        let mut builder = PayloadBuilder::default();
+        let operations = (0..3)
+            .map(|index| {
+                let range = &image[2 * index * block..2 * (index + 1) * block];
+                operation(Type::Replace, builder.blob(range), range, extent(2 * index as u64, 2))
+            })
+            .collect();
+        builder.partition("vbmeta", &image, operations);
+        let payload_path = dir.path("payload.bin");
+        fs::write(&payload_path, builder.build()).unwrap();
+        let mut payload = Payload::new(payload_path).unwrap();
+        let manifest = payload.manifest().unwrap();
+        let partition = &manifest.partitions[0];
+        assert!(is_contiguous(partition));
+
+        let options = ExtractOptions { crc32: true, ..Default::default() };
+        let fast_progress = RefCell::new(Vec::new());
+        let fast_file = dir.path("fast.img");
+        let fast = payload.write_contiguous(partition, &fast_file, image.len() as u64, &options, &|value| fast_progress.borrow_mut().push(value), &|_| {}).unwrap();
+        let general_progress = RefCell::new(Vec::new());
+        let general_file = dir.path("general.img");
+        let mut sink = FileSink::create(&general_file, image.len() as u64).unwrap();
+        let general = payload.write_partition(partition, &mut sink, &options, &|value| general_progress.borrow_mut().push(value), &|_| {}).unwrap();
+        drop(sink);
+
+        assert_eq!(fs::read(&fast_file).unwrap(), image);
+        assert_eq!(fs::read(&general_file).unwrap(), image);
+        assert_eq!(fast.computed_hash, general.computed_hash);
+        assert_eq!(fast.crc32, general.crc32);
+        assert_eq!(fast_progress.into_inner(), general_progress.into_inner());
+    }
+
+    #[test]
+    fn contiguous_copy_checks_operation_hashes() {
+        let dir = ScratchDir::new("contiguous_hashes");
+        let block = BLOCK_SIZE as usize;
+        let image = pattern(4 * block, 4);
+        let mut builder = PayloadBuilder::default();
+        let (head, tail) = image.split_at(2 * block);
+        let mut operations = vec![
+            operation(Type::Replace, builder.blob(head), head, extent(0, 2)),
+            operation(Type::Replace, builder.blob(tail), tail, extent(2, 2)),
+        ];
+        operations[1].data_sha256_hash = Some(sha256(head));
+        builder.partition("dtbo", &image, operations);
+        let payload_path = dir.path("payload.bin");
+        fs::write(&payload_path, builder.build()).unwrap();
+        let mut payload = Payload::new(payload_path).unwrap();
+        assert!(payload.partitions().unwrap()[0].contiguous);
+
+        let out_file = dir.path("dtbo.img");
+        for (hash_granularity, passes) in [(HashGranularity::Both, false), (HashGranularity::PerOperation, false), (HashGranularity::PartitionOnly, true)] {
+            let options = ExtractOptions { hash_granularity, ..Default::default() };
+            let result = payload.extract_with("dtbo", &out_file, &options, &|_| {}, &|_| {});
+            assert_eq!(result.is_ok(), passes, "{:?}", hash_granularity);
+        }
+    }
+
+    #[test]
+    fn output_path_must_be_a_file_or_block_device() {
+        let dir = ScratchDir::new("output_path");
+        let file = dir.path("image.img");
+        fs::write(&file, b"").unwrap();
+        assert!(check_output_path(&file, SymlinkPolicy::Refuse, false).is_ok());
+        assert!(check_output_path(&dir.path("missing.img"), SymlinkPolicy::Refuse, false).is_ok());
+        assert!(check_output_path(&dir.0.to_string_lossy(), SymlinkPolicy::Refuse, true).is_err());
+        #[cfg(unix)]
+        assert!(check_output_path("/dev/null", SymlinkPolicy::Refuse, true).is_err());
+    }
+}
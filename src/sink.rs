@@ -0,0 +1,175 @@
+use std::{fs::{File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}};
+
+/// Where an extracted partition image is written.
+///
+/// Extraction applies operations extent by extent: it seeks to the start of each
+/// destination extent, then writes data or zeros there. Full payloads do this in
+/// ascending order, which is all that sequential sinks need to support.
+pub trait OutputSink {
+    /// Moves the write position to `offset` bytes into the image.
+    fn seek_to(&mut self, offset: u64) -> io::Result<()>;
+
+    /// Writes `data` at the current position and moves past it.
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Fills `len` bytes at the current position with zeros and moves past them.
+    fn write_zeros(&mut self, len: u64) -> io::Result<()>;
+
+    /// Reads back `buf.len()` bytes at `offset`, for sinks that can.
+    fn read_at(&mut self, _offset: u64, _buf: &mut [u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "this output cannot be read back"))
+    }
+
+    /// Whether the sink already holds the previous image, which `SOURCE_COPY` reads from.
+    /// Parts of the image that are never written keep that old content; otherwise they
+    /// read back as zeros.
+    fn holds_source_image(&self) -> bool {
+        false
+    }
+
+    /// The file backing the image, for sinks that write a raw image straight to a file.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+
+    /// Called once every operation has been applied.
+    fn finalize(&mut self) -> io::Result<()>;
+}
+
+/// Writes a raw image into a regular file.
+pub struct FileSink {
+    file: File,
+    in_place: bool,
+    sparse_zeros: bool,
+}
+
+impl FileSink {
+    /// Creates `path`, truncating any existing file, and sizes it to `size` bytes.
+    pub fn create(path: &str, size: u64) -> io::Result<FileSink> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        // Sizing the file up front lets the filesystem allocate it in one go. A freshly
+        // created file then reads back as zeros, so zero ranges can be left as holes.
+        let sparse_zeros = file.set_len(size).is_ok();
+        Ok(FileSink { file, in_place: false, sparse_zeros })
+    }
+
+    /// Opens `path` without truncating it and resizes it to `size` bytes, so the image is
+    /// updated in place. The existing content counts as the source image. Block devices
+    /// can't be resized, so they only need to hold at least `size` bytes.
+    pub fn open_in_place(path: &str, size: u64) -> io::Result<FileSink> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        if file.metadata()?.is_file() {
+            file.set_len(size)?;
+        } else {
+            // Devices report a zero length in their metadata, so seek to the end for their size.
+            let len = file.seek(SeekFrom::End(0))?;
+            if len < size {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} holds {} bytes, the image needs {}", path, len, size)));
+            }
+        }
+        Ok(FileSink { file, in_place: true, sparse_zeros: false })
+    }
+}
+
+impl OutputSink for FileSink {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    fn write_zeros(&mut self, len: u64) -> io::Result<()> {
+        if self.sparse_zeros {
+            return self.file.seek(SeekFrom::Current(len as i64)).map(|_| ());
+        }
+        io::copy(&mut io::repeat(0).take(len), &mut self.file).map(|_| ())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn holds_source_image(&self) -> bool {
+        self.in_place
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes a raw image to any forward-only writer, such as a pipe or socket. Gaps are
+/// filled with zeros and the image is padded to its full size on `finalize`.
+pub struct StreamingSink<W: Write> {
+    writer: W,
+    size: u64,
+    position: u64,
+}
+
+impl<W: Write> StreamingSink<W> {
+    pub fn new(writer: W, size: u64) -> StreamingSink<W> {
+        StreamingSink { writer, size, position: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> OutputSink for StreamingSink<W> {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        if offset < self.position {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "streaming output needs operations in ascending order"));
+        }
+        self.write_zeros(offset - self.position)
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_zeros(&mut self, len: u64) -> io::Result<()> {
+        io::copy(&mut io::repeat(0).take(len), &mut self.writer)?;
+        self.position += len;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.position < self.size {
+            self.write_zeros(self.size - self.position)?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// Discards everything written to it, for checking a partition's hash without keeping
+/// the image.
+#[derive(Default)]
+pub struct NullSink;
+
+impl OutputSink for NullSink {
+    fn seek_to(&mut self, _offset: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_data(&mut self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_zeros(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
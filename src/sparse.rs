@@ -0,0 +1,325 @@
+use std::{fs::File, io::{self, BufReader, Read, Seek, SeekFrom, Write}};
+
+use crate::sink::OutputSink;
+
+const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+const SPARSE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+/// Upper bound for a single RAW chunk, keeping its byte length well inside a `u32`.
+const MAX_RAW_CHUNK: u64 = 64 * 1024 * 1024;
+/// Largest block size accepted when expanding an image. libsparse always writes 4096, and
+/// FILL chunks are expanded a block at a time.
+const MAX_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Writes an Android sparse image, so writes have to arrive in ascending order. Data goes
+/// into RAW chunks and zero ranges into FILL chunks. Ranges that are skipped over become
+/// DONT_CARE chunks, which fastboot leaves untouched on the device.
+pub struct SparseSink<W: Write + Seek> {
+    writer: W,
+    block_size: u32,
+    total_blocks: u32,
+    total_chunks: u32,
+    position: u64,
+    /// Header offset and byte length of the RAW chunk being written, if any.
+    raw_chunk: Option<(u64, u64)>,
+    /// Blocks of a DONT_CARE chunk that has not been written out yet.
+    skipped_blocks: u32,
+    /// Blocks of a zero FILL chunk that has not been written out yet.
+    zero_blocks: u32,
+}
+
+impl<W: Write + Seek> SparseSink<W> {
+    pub fn new(mut writer: W, size: u64, block_size: u32) -> io::Result<SparseSink<W>> {
+        let total_blocks = u32::try_from(size.div_ceil(block_size as u64))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "image too large for the sparse format"))?;
+        writer.write_all(&[0; SPARSE_HEADER_SIZE as usize])?;
+        Ok(SparseSink { writer, block_size, total_blocks, total_chunks: 0, position: 0, raw_chunk: None, skipped_blocks: 0, zero_blocks: 0 })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_chunk_header(&mut self, chunk_type: u16, blocks: u32, data_len: u64) -> io::Result<()> {
+        let mut header = Vec::with_capacity(CHUNK_HEADER_SIZE as usize);
+        header.extend_from_slice(&chunk_type.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&blocks.to_le_bytes());
+        header.extend_from_slice(&(CHUNK_HEADER_SIZE as u32 + data_len as u32).to_le_bytes());
+        self.writer.write_all(&header)
+    }
+
+    fn close_raw_chunk(&mut self) -> io::Result<()> {
+        let Some((header_offset, mut len)) = self.raw_chunk.take() else {
+            return Ok(());
+        };
+        let padding = len.next_multiple_of(self.block_size as u64) - len;
+        if padding > 0 {
+            self.writer.write_all(&vec![0; padding as usize])?;
+            self.position += padding;
+            len += padding;
+        }
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(header_offset))?;
+        self.write_chunk_header(CHUNK_TYPE_RAW, (len / self.block_size as u64) as u32, len)?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.total_chunks += 1;
+        Ok(())
+    }
+
+    fn close_skip_chunk(&mut self) -> io::Result<()> {
+        if self.skipped_blocks > 0 {
+            self.write_chunk_header(CHUNK_TYPE_DONT_CARE, self.skipped_blocks, 0)?;
+            self.total_chunks += 1;
+            self.skipped_blocks = 0;
+        }
+        Ok(())
+    }
+
+    fn close_zero_chunk(&mut self) -> io::Result<()> {
+        if self.zero_blocks > 0 {
+            self.write_chunk_header(CHUNK_TYPE_FILL, self.zero_blocks, 4)?;
+            self.writer.write_all(&0u32.to_le_bytes())?;
+            self.total_chunks += 1;
+            self.zero_blocks = 0;
+        }
+        Ok(())
+    }
+
+    fn blocks(&self, len: u64) -> io::Result<u32> {
+        if !len.is_multiple_of(self.block_size as u64) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sparse output needs block-aligned zero ranges"));
+        }
+        Ok((len / self.block_size as u64) as u32)
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        let blocks = self.blocks(len)?;
+        self.close_raw_chunk()?;
+        self.close_zero_chunk()?;
+        self.skipped_blocks += blocks;
+        self.position += len;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> OutputSink for SparseSink<W> {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        if offset < self.position {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "sparse output needs operations in ascending order"));
+        }
+        if offset > self.position {
+            self.skip(offset - self.position)?;
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let len = match self.raw_chunk {
+                Some((_, len)) if len < MAX_RAW_CHUNK => len,
+                _ => {
+                    self.close_raw_chunk()?;
+                    self.close_skip_chunk()?;
+                    self.close_zero_chunk()?;
+                    let header_offset = self.writer.stream_position()?;
+                    self.writer.write_all(&[0; CHUNK_HEADER_SIZE as usize])?;
+                    self.raw_chunk = Some((header_offset, 0));
+                    0
+                }
+            };
+            let count = data.len().min((MAX_RAW_CHUNK - len) as usize);
+            self.writer.write_all(&data[..count])?;
+            self.raw_chunk = self.raw_chunk.map(|(offset, len)| (offset, len + count as u64));
+            self.position += count as u64;
+            data = &data[count..];
+        }
+        Ok(())
+    }
+
+    fn write_zeros(&mut self, len: u64) -> io::Result<()> {
+        let blocks = self.blocks(len)?;
+        self.close_raw_chunk()?;
+        self.close_skip_chunk()?;
+        self.zero_blocks += blocks;
+        self.position += len;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.close_raw_chunk()?;
+        self.close_zero_chunk()?;
+        let end = self.total_blocks as u64 * self.block_size as u64;
+        if self.position < end {
+            self.skip(end - self.position)?;
+        }
+        self.close_skip_chunk()?;
+
+        let mut header = Vec::with_capacity(SPARSE_HEADER_SIZE as usize);
+        header.extend_from_slice(&SPARSE_HEADER_MAGIC.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&SPARSE_HEADER_SIZE.to_le_bytes());
+        header.extend_from_slice(&CHUNK_HEADER_SIZE.to_le_bytes());
+        header.extend_from_slice(&self.block_size.to_le_bytes());
+        header.extend_from_slice(&self.total_blocks.to_le_bytes());
+        header.extend_from_slice(&self.total_chunks.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn le16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn le32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// Expands the Android sparse image at `input_path` into a raw image at `output_path`.
+/// DONT_CARE chunks come out as zeros, left as holes where the filesystem supports it.
+pub fn desparse(input_path: &str, output_path: &str) -> io::Result<()> {
+    let mut input = BufReader::new(File::open(input_path)?);
+
+    let mut header = [0; SPARSE_HEADER_SIZE as usize];
+    input.read_exact(&mut header).map_err(|_| invalid(format!("{} is too short for a sparse image header", input_path)))?;
+    if le32(&header, 0) != SPARSE_HEADER_MAGIC {
+        return Err(invalid(format!("{} is not an Android sparse image", input_path)));
+    }
+    let major_version = le16(&header, 4);
+    if major_version != 1 {
+        return Err(invalid(format!("unsupported sparse image version {}", major_version)));
+    }
+    let file_header_size = le16(&header, 8);
+    let chunk_header_size = le16(&header, 10);
+    let block_size = le32(&header, 12) as u64;
+    let total_blocks = le32(&header, 16) as u64;
+    let total_chunks = le32(&header, 20);
+    if file_header_size < SPARSE_HEADER_SIZE || chunk_header_size < CHUNK_HEADER_SIZE {
+        return Err(invalid(format!("invalid sparse header sizes {} and {}", file_header_size, chunk_header_size)));
+    }
+    if block_size == 0 || !block_size.is_multiple_of(4) || block_size > MAX_BLOCK_SIZE {
+        return Err(invalid(format!("invalid sparse block size {}", block_size)));
+    }
+    io::copy(&mut (&mut input).take((file_header_size - SPARSE_HEADER_SIZE) as u64), &mut io::sink())?;
+
+    let mut output = File::create(output_path)?;
+    let mut chunk_header = vec![0; chunk_header_size as usize];
+    let mut blocks: u64 = 0;
+    for chunk in 0..total_chunks {
+        input.read_exact(&mut chunk_header).map_err(|_| invalid(format!("sparse image is truncated at chunk {}", chunk)))?;
+        let chunk_type = le16(&chunk_header, 0);
+        let chunk_blocks = le32(&chunk_header, 4) as u64;
+        let data_len = (le32(&chunk_header, 8) as u64)
+            .checked_sub(chunk_header_size as u64)
+            .ok_or_else(|| invalid(format!("chunk {} is smaller than its header", chunk)))?;
+        if blocks + chunk_blocks > total_blocks {
+            return Err(invalid(format!("chunk {} runs past the {} blocks declared in the header", chunk, total_blocks)));
+        }
+        let out_len = chunk_blocks * block_size;
+        let expected_data_len = match chunk_type {
+            CHUNK_TYPE_RAW => out_len,
+            CHUNK_TYPE_FILL | CHUNK_TYPE_CRC32 => 4,
+            CHUNK_TYPE_DONT_CARE => 0,
+            other => return Err(invalid(format!("chunk {} has unknown type {:#06x}", chunk, other))),
+        };
+        if data_len != expected_data_len {
+            return Err(invalid(format!("chunk {} holds {} bytes of data, expected {}", chunk, data_len, expected_data_len)));
+        }
+
+        match chunk_type {
+            CHUNK_TYPE_RAW => {
+                if io::copy(&mut (&mut input).take(data_len), &mut output)? != data_len {
+                    return Err(invalid(format!("sparse image is truncated in chunk {}", chunk)));
+                }
+            }
+            CHUNK_TYPE_FILL => {
+                let mut fill = [0; 4];
+                input.read_exact(&mut fill).map_err(|_| invalid(format!("sparse image is truncated in chunk {}", chunk)))?;
+                let block: Vec<u8> = fill.iter().copied().cycle().take(block_size as usize).collect();
+                for _ in 0..chunk_blocks {
+                    output.write_all(&block)?;
+                }
+            }
+            CHUNK_TYPE_DONT_CARE => {
+                output.seek(SeekFrom::Current(out_len as i64))?;
+            }
+            _ => {
+                // The CRC32 chunk only checks what came before it and covers no blocks.
+                if chunk_blocks != 0 {
+                    return Err(invalid(format!("CRC32 chunk {} claims {} blocks", chunk, chunk_blocks)));
+                }
+                input.read_exact(&mut [0; 4]).map_err(|_| invalid(format!("sparse image is truncated in chunk {}", chunk)))?;
+            }
+        }
+        blocks += chunk_blocks;
+    }
+    if blocks != total_blocks {
+        return Err(invalid(format!("chunks cover {} blocks but the header declares {}", blocks, total_blocks)));
+    }
+
+    output.set_len(total_blocks * block_size)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCK: usize = 4096;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("sparse-test-{}-{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    /// Writes `sparse` out, expands it and returns the raw image.
+    fn expand(name: &str, sparse: &[u8]) -> io::Result<Vec<u8>> {
+        let (input, output) = (temp_path(&format!("{}.sparse", name)), temp_path(&format!("{}.img", name)));
+        std::fs::write(&input, sparse)?;
+        let result = desparse(&input, &output).and_then(|_| std::fs::read(&output));
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+        result
+    }
+
+    #[test]
+    fn sparse_sink_round_trips() {
+        let data: Vec<u8> = (0..3 * BLOCK).map(|i| (i * 7 % 251) as u8).collect();
+        let mut sink = SparseSink::new(Cursor::new(Vec::new()), 10 * BLOCK as u64 + 100, BLOCK as u32).unwrap();
+        sink.write_data(&data[..BLOCK]).unwrap();
+        sink.seek_to(2 * BLOCK as u64).unwrap();
+        sink.write_zeros(2 * BLOCK as u64).unwrap();
+        sink.seek_to(6 * BLOCK as u64).unwrap();
+        sink.write_data(&data[BLOCK..]).unwrap();
+        sink.finalize().unwrap();
+
+        let mut expected = vec![0; 11 * BLOCK];
+        expected[..BLOCK].copy_from_slice(&data[..BLOCK]);
+        expected[6 * BLOCK..8 * BLOCK].copy_from_slice(&data[BLOCK..]);
+        assert_eq!(expand("round_trip", &sink.into_inner().into_inner()).unwrap(), expected);
+    }
+
+    #[test]
+    fn oversized_block_size_is_refused() {
+        let mut sink = SparseSink::new(Cursor::new(Vec::new()), BLOCK as u64, BLOCK as u32).unwrap();
+        sink.write_zeros(BLOCK as u64).unwrap();
+        sink.finalize().unwrap();
+        let mut sparse = sink.into_inner().into_inner();
+        sparse[12..16].copy_from_slice(&0xfffffffcu32.to_le_bytes());
+        let err = expand("block_size", &sparse).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}